@@ -1,12 +1,13 @@
-advent_of_code::solution!(16);
+advent_of_code::solution!(@impl 16, [part_one, 1] [cli_part_two, 2]);
 
 use advent_of_code::template::RunType;
+use aoc_solutions::cli::Args;
 
 use aoc_lib::grid::{Direction, Location, Map};
 
 use anyhow::{anyhow, Context, Result};
 use core::cmp::Reverse;
-use std::collections::{BTreeSet, BinaryHeap};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
 
 #[derive(Debug, Clone, PartialEq)]
 enum Tile {
@@ -111,49 +112,105 @@ fn min_cost(cost_map: &CostMap, start: &Location) -> Result<usize> {
         .context("Expected to find a route to target")
 }
 
-fn iter_paths(
-    cost_map: &CostMap,
-    current: &Location,
-    target: &Location,
-    direction: &Direction,
-    allowed_cost: usize,
-    seen: &mut BTreeSet<Location>,
-) {
-    seen.insert(current.clone());
-    seen.insert(target.clone());
-    if current == target {
-        return;
-    }
+/// Every tile on at least one cheapest route from start to end, and how many distinct
+/// routes there are, counting routes that visit the same tiles in the same order once.
+#[derive(Debug, Clone, PartialEq)]
+struct BestSeats {
+    tiles: BTreeSet<Location>,
+    paths: usize,
+}
 
-    let costs = cost_map.get(current);
-    if costs[direction.idx()].is_some() {
-        iter_paths(
-            cost_map,
-            &cost_map.go_direction(current, direction).unwrap(),
-            target,
-            direction,
-            allowed_cost - 1,
-            seen,
-        );
+/// Follows the cost map forward from the start. A cost map entry is the cost of stepping
+/// forward from a tile while facing a direction, so after each step we may carry on or turn
+/// and only keep the choices where the cost still adds up to the cheapest route.
+fn best_seats(cost_map: &CostMap, start: &Location, target: &Location) -> Result<BestSeats> {
+    let min_cost = min_cost(cost_map, start)?;
+    let cost = |loc: &Location, direction: &Direction| cost_map.get(loc)[direction.idx()];
+
+    let mut first = Vec::new();
+    for (direction, turns) in [
+        (Direction::East, 0),
+        (Direction::North, 1000),
+        (Direction::South, 1000),
+        (Direction::West, 2000),
+    ] {
+        if cost(start, &direction).map(|c| c + turns) == Some(min_cost) {
+            first.push((start.clone(), direction));
+        }
     }
 
-    for option in [direction.left(), direction.right()] {
-        if let Some(cost) = costs[option.idx()] {
-            if cost <= allowed_cost {
-                iter_paths(
-                    cost_map,
-                    current,
-                    target,
-                    &option,
-                    allowed_cost - 1000,
-                    seen,
-                );
+    let next_states = |loc: &Location, direction: &Direction| {
+        let mut out = Vec::new();
+        let remaining = cost(loc, direction).unwrap() - 1;
+        let next = cost_map.go_direction(loc, direction).unwrap();
+        if &next == target {
+            return out;
+        }
+        for (option, turns) in [
+            (direction.clone(), 0),
+            (direction.left(), 1000),
+            (direction.right(), 1000),
+        ] {
+            if cost(&next, &option).map(|c| c + turns) == Some(remaining) {
+                out.push((next.clone(), option));
             }
         }
+        out
+    };
+
+    // Collect the states that lie on a cheapest route
+    let mut states = BTreeSet::new();
+    let mut to_visit = first.clone();
+    while let Some(state) = to_visit.pop() {
+        if states.insert(state.clone()) {
+            to_visit.extend(next_states(&state.0, &state.1));
+        }
     }
+
+    // Costs only go down along a route, so counting from the cheapest states up means every
+    // state after us has already been counted
+    let mut ordered: Vec<_> = states.iter().collect();
+    ordered.sort_by_key(|(loc, direction)| cost(loc, direction));
+    let mut ways: BTreeMap<(Location, Direction), usize> = BTreeMap::new();
+    for (loc, direction) in ordered {
+        let next = next_states(loc, direction);
+        let count = if next.is_empty() {
+            1
+        } else {
+            next.iter().map(|state| ways[state]).sum()
+        };
+        ways.insert((loc.clone(), direction.clone()), count);
+    }
+
+    let mut tiles: BTreeSet<Location> = states.into_iter().map(|(loc, _)| loc).collect();
+    tiles.insert(target.clone());
+    let paths = first.iter().map(|state| ways[state]).sum();
+
+    Ok(BestSeats { tiles, paths })
 }
 
-fn setup(input: &str) -> Result<(CostMap, Location, Location)> {
+/// Draws the maze like the puzzle does, with every tile on a cheapest route marked `O`.
+fn render(map: &Map<Tile>, seats: &BestSeats) -> String {
+    let mut out = String::new();
+    for row in map.iter() {
+        for (loc, tile) in row {
+            out.push(if seats.tiles.contains(&loc) {
+                'O'
+            } else {
+                match tile {
+                    Tile::Start => 'S',
+                    Tile::End => 'E',
+                    Tile::Empty => '.',
+                    Tile::Wall => '#',
+                }
+            });
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn setup(input: &str) -> Result<(Map<Tile>, CostMap, Location, Location)> {
     let map: Map<Tile> = input.try_into().context("failed to parse input")?;
 
     let start = map
@@ -164,30 +221,36 @@ fn setup(input: &str) -> Result<(CostMap, Location, Location)> {
         .context("failed to find end")?;
 
     let cost_map = build_cost_map(&map, &end).context("Failed to seek path")?;
-    Ok((cost_map, start, end))
+    Ok((map, cost_map, start, end))
 }
 
 pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
-    let (cost_map, start, _) = setup(input)?;
+    let (_, cost_map, start, _) = setup(input)?;
     let min_cost = min_cost(&cost_map, &start)?;
 
     Ok(Some(min_cost))
 }
 
 pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
-    let (cost_map, start, end) = setup(input)?;
-    let min_cost = min_cost(&cost_map, &start)?;
-    let mut locs = BTreeSet::new();
-    iter_paths(
-        &cost_map,
-        &start,
-        &end,
-        &Direction::East,
-        min_cost,
-        &mut locs,
-    );
-
-    Ok(Some(locs.len()))
+    let (_, cost_map, start, end) = setup(input)?;
+    let seats = best_seats(&cost_map, &start, &end)?;
+
+    Ok(Some(seats.tiles.len()))
+}
+
+/// Part two from the command line. `--show` draws the best seats like the puzzle does and
+/// says how many cheapest routes there are.
+fn cli_part_two(input: &str, run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
+    if !Args::from_env().flag("show") {
+        return part_two(input, run_type);
+    }
+
+    let (map, cost_map, start, end) = setup(input)?;
+    let seats = best_seats(&cost_map, &start, &end)?;
+    print!("{}", render(&map, &seats));
+    println!("{} cheapest routes", seats.paths);
+
+    Ok(Some(seats.tiles.len()))
 }
 
 #[cfg(test)]
@@ -213,4 +276,80 @@ mod tests_day_16 {
         assert_eq!(result, expected);
         Ok(())
     }
+
+    #[test]
+    fn test_best_seats() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let (map, cost_map, start, end) = setup(input)?;
+        let seats = best_seats(&cost_map, &start, &end)?;
+        assert_eq!(seats.paths, 3);
+        assert_eq!(
+            render(&map, &seats),
+            "\
+###############
+#.......#....O#
+#.#.###.#.###O#
+#.....#.#...#O#
+#.###.#####.#O#
+#.#.#.......#O#
+#.#.#####.###O#
+#..OOOOOOOOO#O#
+###O#O#####O#O#
+#OOO#O....#O#O#
+#O#O#O###.#O#O#
+#OOOOO#...#O#O#
+#O###.#.#.#O#O#
+#O..#.....#OOO#
+###############
+"
+        );
+
+        let input = "\
+#################
+#...#...#...#..E#
+#.#.#.#.#.#.#.#.#
+#.#.#.#...#...#.#
+#.#.#.#.###.#.#.#
+#...#.#.#.....#.#
+#.#.#.#.#.#####.#
+#.#...#.#.#.....#
+#.#.#####.#.###.#
+#.#.#.......#...#
+#.#.###.#####.###
+#.#.#...#.....#.#
+#.#.#.#####.###.#
+#.#.#.........#.#
+#.#.#.#########.#
+#S#.............#
+#################
+";
+        let (map, cost_map, start, end) = setup(input)?;
+        let seats = best_seats(&cost_map, &start, &end)?;
+        assert_eq!(min_cost(&cost_map, &start)?, 11048);
+        assert_eq!(seats.tiles.len(), 64);
+        assert_eq!(seats.paths, 2);
+        assert_eq!(
+            render(&map, &seats),
+            "\
+#################
+#...#...#...#..O#
+#.#.#.#.#.#.#.#O#
+#.#.#.#...#...#O#
+#.#.#.#.###.#.#O#
+#OOO#.#.#.....#O#
+#O#O#.#.#.#####O#
+#O#O..#.#.#OOOOO#
+#O#O#####.#O###O#
+#O#O#..OOOOO#OOO#
+#O#O###O#####O###
+#O#O#OOO#..OOO#.#
+#O#O#O#####O###.#
+#O#O#OOOOOOO..#.#
+#O#O#O#########.#
+#O#OOO..........#
+#################
+"
+        );
+        Ok(())
+    }
 }
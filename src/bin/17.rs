@@ -1,10 +1,24 @@
-advent_of_code::solution!(17);
+advent_of_code::solution!(@impl 17, [cli_part_one, 1] [part_two, 2]);
 
 use advent_of_code::template::RunType;
+use aoc_solutions::cli::Args;
 
 use anyhow::{anyhow, Context, Result};
 use std::collections::BTreeMap;
 
+// Enough for any sensible program, the real inputs take a few hundred steps
+const STEP_LIMIT: usize = 1_000_000;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+enum VmError {
+    #[error("program did not halt within {0} steps")]
+    StepLimit(usize),
+    #[error("op at ip={0} uses reserved combo operand 7")]
+    InvalidCombo(u64),
+    #[error("op at ip={0} divides by 2^{1} which does not fit in a u64")]
+    Overflow(u64, u64),
+}
+
 #[derive(Debug, Clone)]
 struct Registers([u64; 3]);
 
@@ -47,8 +61,8 @@ impl Combo {
         Ok(Self(value))
     }
 
-    fn resolve(&self, registers: &Registers) -> u64 {
-        match self.0 {
+    fn resolve(&self, registers: &Registers, ip: u64) -> Result<u64, VmError> {
+        Ok(match self.0 {
             0 => 0,
             1 => 1,
             2 => 2,
@@ -56,8 +70,18 @@ impl Combo {
             4 => registers.a(),
             5 => registers.b(),
             6 => registers.c(),
-            _ => unreachable!(),
-        }
+            _ => return Err(VmError::InvalidCombo(ip)),
+        })
+    }
+
+    // The dv ops all divide A by 2 to the power of the operand
+    fn divide(&self, registers: &Registers, ip: u64) -> Result<u64, VmError> {
+        let power = self.resolve(registers, ip)?;
+        let divisor = u32::try_from(power)
+            .ok()
+            .and_then(|p| 2_u64.checked_pow(p))
+            .ok_or(VmError::Overflow(ip, power))?;
+        Ok(registers.a() / divisor)
     }
 
     fn value_str(&self) -> &str {
@@ -69,9 +93,26 @@ impl Combo {
             4 => "%A",
             5 => "%B",
             6 => "%C",
-            _ => unreachable!(),
+            // Reserved, faults if it is ever run
+            _ => "7",
         }
     }
+
+    fn parse(value: &str) -> Result<Self> {
+        Ok(Self(match value {
+            "0" => 0,
+            "1" => 1,
+            "2" => 2,
+            "3" => 3,
+            "%A" => 4,
+            "%B" => 5,
+            "%C" => 6,
+            "7" => 7,
+            other => {
+                return Err(anyhow!("found unexpected combo operand {:?}", other));
+            }
+        }))
+    }
 }
 
 #[derive(Debug, Clone, Ord, Eq, PartialEq, PartialOrd, Hash)]
@@ -80,7 +121,7 @@ pub enum Op {
     Bxl(Literal),
     Bst(Combo),
     Jnz(Literal),
-    Bxc,
+    Bxc(Literal),
     Out(Combo),
     Bdb(Combo),
     Cdv(Combo),
@@ -89,14 +130,38 @@ pub enum Op {
 impl Op {
     fn name(&self) -> &str {
         match self {
-            Self::Adv(_) => "Adv",
-            Self::Bxl(_) => "Bxl",
-            Self::Bst(_) => "Bst",
-            Self::Jnz(_) => "Jnz",
-            Self::Bxc => "Bxc",
-            Self::Out(_) => "Out",
-            Self::Bdb(_) => "Bdb",
-            Self::Cdv(_) => "Cdv",
+            Self::Adv(_) => "adv",
+            Self::Bxl(_) => "bxl",
+            Self::Bst(_) => "bst",
+            Self::Jnz(_) => "jnz",
+            Self::Bxc(_) => "bxc",
+            Self::Out(_) => "out",
+            Self::Bdb(_) => "bdv",
+            Self::Cdv(_) => "cdv",
+        }
+    }
+
+    fn opcode(&self) -> u8 {
+        match self {
+            Self::Adv(_) => 0,
+            Self::Bxl(_) => 1,
+            Self::Bst(_) => 2,
+            Self::Jnz(_) => 3,
+            Self::Bxc(_) => 4,
+            Self::Out(_) => 5,
+            Self::Bdb(_) => 6,
+            Self::Cdv(_) => 7,
+        }
+    }
+
+    fn operand(&self) -> u8 {
+        match self {
+            Self::Adv(combo)
+            | Self::Bst(combo)
+            | Self::Out(combo)
+            | Self::Bdb(combo)
+            | Self::Cdv(combo) => combo.0,
+            Self::Bxl(literal) | Self::Jnz(literal) | Self::Bxc(literal) => literal.0,
         }
     }
 
@@ -108,41 +173,43 @@ impl Op {
             | Self::Bdb(combo)
             | Self::Cdv(combo) => combo.value_str().to_string(),
             Self::Bxl(literal) | Self::Jnz(literal) => format!("{}", literal.0),
-            Self::Bxc => String::new(),
+            // bxc ignores its operand, only show it when it isn't the usual 0
+            Self::Bxc(Literal(0)) => String::new(),
+            Self::Bxc(literal) => format!("{}", literal.0),
         }
     }
 
-    fn run(&self, registers: &mut Registers, ip: u64) -> (Option<u8>, u64) {
+    fn run(&self, registers: &mut Registers, ip: u64) -> Result<(Option<u8>, u64), VmError> {
         match self {
             Self::Adv(combo) => {
-                *registers.a_mut() = registers.a() / (2_u64).pow(combo.resolve(registers) as u32);
+                *registers.a_mut() = combo.divide(registers, ip)?;
             }
             Self::Bxl(literal) => {
                 *registers.b_mut() = registers.b() ^ literal.0 as u64;
             }
             Self::Bst(combo) => {
-                *registers.b_mut() = combo.resolve(registers) % 8;
+                *registers.b_mut() = combo.resolve(registers, ip)? % 8;
             }
             Self::Jnz(literal) => {
                 if registers.a() != 0 {
-                    return (None, literal.0 as u64 / 2);
+                    return Ok((None, literal.0 as u64 / 2));
                 }
             }
-            Self::Bxc => {
+            Self::Bxc(_) => {
                 *registers.b_mut() = registers.b() ^ registers.c();
             }
             Self::Out(combo) => {
-                return (Some((combo.resolve(registers) % 8) as u8), ip + 1);
+                return Ok((Some((combo.resolve(registers, ip)? % 8) as u8), ip + 1));
             }
             Self::Bdb(combo) => {
-                *registers.b_mut() = registers.a() / (2_u64).pow(combo.resolve(registers) as u32);
+                *registers.b_mut() = combo.divide(registers, ip)?;
             }
             Self::Cdv(combo) => {
-                *registers.c_mut() = registers.a() / (2_u64).pow(combo.resolve(registers) as u32);
+                *registers.c_mut() = combo.divide(registers, ip)?;
             }
         }
 
-        (None, ip + 1)
+        Ok((None, ip + 1))
     }
 }
 
@@ -157,7 +224,7 @@ impl TryFrom<(u8, u8)> for Op {
             1 => Op::Bxl(Literal(operand)),
             2 => Op::Bst(Combo::new(operand)?),
             3 => Op::Jnz(Literal(operand)),
-            4 => Op::Bxc,
+            4 => Op::Bxc(Literal(operand)),
             5 => Op::Out(Combo::new(operand)?),
             6 => Op::Bdb(Combo::new(operand)?),
             7 => Op::Cdv(Combo::new(operand)?),
@@ -206,27 +273,301 @@ fn parse(input: &str) -> Result<(Vec<u8>, BTreeMap<char, u64>)> {
     Ok((ops, regs))
 }
 
-fn run_program(program: &[Op], mut registers: Registers) -> Vec<u8> {
+fn build_program(program_ops: &[u8]) -> Result<Vec<Op>> {
+    let mut program = Vec::new();
+    for chunk in program_ops.chunks(2) {
+        let [op_id, operand] = chunk else {
+            return Err(anyhow!("expected every op to have an operand"));
+        };
+        program.push(Op::try_from((*op_id, *operand)).context("failed to build op")?);
+    }
+    Ok(program)
+}
+
+/// Renders the program as one op per line, numbered by instruction pointer, with the
+/// destination of every jump resolved to the op it lands on.
+fn disassemble(program: &[Op]) -> String {
+    let mut out = Vec::new();
+    for (ip, op) in program.iter().enumerate() {
+        let mut line = format!("{:>3}: {} {}", ip, op.name(), op.operand_str());
+        if let Op::Jnz(literal) = op {
+            let target = literal.0 as usize / 2;
+            match program.get(target) {
+                Some(dest) => line.push_str(&format!(
+                    " ; -> {}: {} {}",
+                    target,
+                    dest.name(),
+                    dest.operand_str()
+                )),
+                None => line.push_str(" ; -> halt"),
+            }
+        }
+        out.push(line.trim_end().to_string());
+    }
+    out.join("\n")
+}
+
+/// Turns a listing such as the one produced by `disassemble` back into the raw
+/// comma separated program. Line numbers and `;` comments are optional.
+fn assemble(listing: &str) -> Result<String> {
+    let mut program_ops = Vec::new();
+    for line in listing.lines() {
+        let line = match line.split_once(';') {
+            Some((code, _)) => code,
+            None => line,
+        };
+        let line = match line.split_once(':') {
+            Some((_, code)) => code,
+            None => line,
+        };
+        let mut parts = line.split_whitespace();
+        let Some(name) = parts.next() else {
+            continue;
+        };
+        let operand = parts.next();
+        if let Some(extra) = parts.next() {
+            return Err(anyhow!("unexpected trailing operand {:?}", extra));
+        }
+
+        let literal = || -> Result<u8> {
+            let value = operand.context(format!("expected {} to have an operand", name))?;
+            let value: u8 = value
+                .parse()
+                .context(format!("failed to parse literal operand {:?}", value))?;
+            if value > 7 {
+                return Err(anyhow!("literal operand {} is not 3 bits", value));
+            }
+            Ok(value)
+        };
+        let combo = || -> Result<u8> {
+            Ok(Combo::parse(operand.context(format!("expected {} to have an operand", name))?)?.0)
+        };
+
+        let (op_id, operand) = match name.to_lowercase().as_str() {
+            "adv" => (0, combo()?),
+            "bxl" => (1, literal()?),
+            "bst" => (2, combo()?),
+            "jnz" => (3, literal()?),
+            "bxc" => (4, operand.map_or(Ok(0), |_| literal())?),
+            "out" => (5, combo()?),
+            "bdv" => (6, combo()?),
+            "cdv" => (7, combo()?),
+            other => {
+                return Err(anyhow!("found unexpected op name {:?}", other));
+            }
+        };
+
+        let op = Op::try_from((op_id, operand)).context("failed to build op")?;
+        program_ops.push(op.opcode());
+        program_ops.push(op.operand());
+    }
+
+    let out: Vec<String> = program_ops.into_iter().map(|v| format!("{}", v)).collect();
+    Ok(out.join(","))
+}
+
+fn run_program(program: &[Op], mut registers: Registers) -> Result<Vec<u8>, VmError> {
     let mut out = Vec::new();
     let mut ip: u64 = 0;
+    let mut steps = 0;
     while (ip as usize) < program.len() {
+        if steps >= STEP_LIMIT {
+            return Err(VmError::StepLimit(STEP_LIMIT));
+        }
+        steps += 1;
+
         let op = &program[ip as usize];
-        let ret = op.run(&mut registers, ip);
+        let ret = op.run(&mut registers, ip)?;
         if let Some(cmd_out) = ret.0 {
             out.push(cmd_out);
         }
         ip = ret.1;
     }
-    out
+    Ok(out)
 }
 
-pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<String>, anyhow::Error> {
-    let (program_ops, registers_map) = parse(input)?;
+#[derive(Debug, Clone)]
+struct TraceStep {
+    ip: u64,
+    op: Op,
+    before: Registers,
+    after: Registers,
+    output: Option<u8>,
+}
 
-    let mut program = Vec::new();
-    for chunk in program_ops.chunks(2) {
-        program.push(Op::try_from((chunk[0], chunk[1])).context("failed to build op")?);
+impl TraceStep {
+    fn to_json(&self) -> String {
+        let output = match self.output {
+            Some(v) => format!("{}", v),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"ip\":{},\"op\":\"{}\",\"before\":[{},{},{}],\"after\":[{},{},{}],\"output\":{}}}",
+            self.ip,
+            format!("{} {}", self.op.name(), self.op.operand_str()).trim_end(),
+            self.before.a(),
+            self.before.b(),
+            self.before.c(),
+            self.after.a(),
+            self.after.b(),
+            self.after.c(),
+            output,
+        )
+    }
+}
+
+/// Stops execution before the op at an ip runs, or as soon as a register compares
+/// to a value in the given way.
+#[derive(Debug, Clone, PartialEq)]
+enum Breakpoint {
+    Ip(u64),
+    Register(usize, std::cmp::Ordering, u64),
+}
+
+impl Breakpoint {
+    fn hit(&self, ip: u64, registers: &Registers) -> bool {
+        match self {
+            Self::Ip(target) => ip == *target,
+            Self::Register(idx, ordering, value) => registers.0[*idx].cmp(value) == *ordering,
+        }
+    }
+}
+
+impl std::str::FromStr for Breakpoint {
+    type Err = anyhow::Error;
+
+    // Accepts `ip=3`, `A=0`, `B<8` or `C>100`
+    fn from_str(other: &str) -> Result<Self> {
+        let split = other.find(['=', '<', '>']).context(format!(
+            "expected breakpoint to contain =, < or > {:?}",
+            other
+        ))?;
+        let (target, rest) = other.split_at(split);
+        let (cmp, value) = rest.split_at(1);
+        let value: u64 = value
+            .parse()
+            .context(format!("failed to parse breakpoint value {:?}", value))?;
+        let ordering = match cmp {
+            "=" => std::cmp::Ordering::Equal,
+            "<" => std::cmp::Ordering::Less,
+            _ => std::cmp::Ordering::Greater,
+        };
+
+        Ok(match (target, ordering) {
+            ("ip", std::cmp::Ordering::Equal) => Self::Ip(value),
+            ("A", _) => Self::Register(0, ordering, value),
+            ("B", _) => Self::Register(1, ordering, value),
+            ("C", _) => Self::Register(2, ordering, value),
+            _ => {
+                return Err(anyhow!("found unexpected breakpoint {:?}", other));
+            }
+        })
     }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Stop {
+    Halted,
+    Breakpoint(usize),
+}
+
+/// Runs a program one op at a time, keeping a trace of every step taken.
+struct Debugger<'a> {
+    program: &'a [Op],
+    registers: Registers,
+    ip: u64,
+    breakpoints: Vec<Breakpoint>,
+    trace: Vec<TraceStep>,
+    // How many steps had been taken when `run` last stopped at a breakpoint
+    stopped_at: Option<usize>,
+}
+
+impl<'a> Debugger<'a> {
+    fn new(program: &'a [Op], registers: Registers) -> Self {
+        Self {
+            program,
+            registers,
+            ip: 0,
+            breakpoints: Vec::new(),
+            trace: Vec::new(),
+            stopped_at: None,
+        }
+    }
+
+    fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    fn halted(&self) -> bool {
+        (self.ip as usize) >= self.program.len()
+    }
+
+    fn step(&mut self) -> Result<Option<&TraceStep>, VmError> {
+        if self.halted() {
+            return Ok(None);
+        }
+        if self.trace.len() >= STEP_LIMIT {
+            return Err(VmError::StepLimit(STEP_LIMIT));
+        }
+        let op = &self.program[self.ip as usize];
+        let before = self.registers.clone();
+        let (output, next_ip) = op.run(&mut self.registers, self.ip)?;
+        self.trace.push(TraceStep {
+            ip: self.ip,
+            op: op.clone(),
+            before,
+            after: self.registers.clone(),
+            output,
+        });
+        self.ip = next_ip;
+        Ok(self.trace.last())
+    }
+
+    /// Runs until the program halts or a breakpoint is hit, checking breakpoints before
+    /// every step including the first. Calling this again right after a breakpoint
+    /// continues past it, but a step taken in between means it is checked again.
+    fn run(&mut self) -> Result<Stop, VmError> {
+        let resumed = self.stopped_at.take() == Some(self.trace.len());
+        let mut first = true;
+        while !self.halted() {
+            if !(first && resumed) {
+                if let Some(idx) = self
+                    .breakpoints
+                    .iter()
+                    .position(|b| b.hit(self.ip, &self.registers))
+                {
+                    self.stopped_at = Some(self.trace.len());
+                    return Ok(Stop::Breakpoint(idx));
+                }
+            }
+            first = false;
+            self.step()?;
+        }
+        Ok(Stop::Halted)
+    }
+
+    /// Runs until the program halts, passing every breakpoint hit to `report` on the way.
+    /// Faults stop the run and are returned as they are.
+    fn run_to_halt(&mut self, mut report: impl FnMut(&Self, usize)) -> Result<(), VmError> {
+        while let Stop::Breakpoint(idx) = self.run()? {
+            report(self, idx);
+        }
+        Ok(())
+    }
+
+    fn output(&self) -> Vec<u8> {
+        self.trace.iter().filter_map(|step| step.output).collect()
+    }
+
+    fn trace_json_lines(&self) -> String {
+        let lines: Vec<String> = self.trace.iter().map(|step| step.to_json()).collect();
+        lines.join("\n")
+    }
+}
+
+fn setup(input: &str) -> Result<(Vec<u8>, Vec<Op>, Registers)> {
+    let (program_ops, registers_map) = parse(input)?;
+    let program = build_program(&program_ops)?;
 
     let registers = Registers([
         *registers_map
@@ -240,89 +581,398 @@ pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<String>, anyho
             .context("expected to find the C register")?,
     ]);
 
-    let out = run_program(&program, registers);
-    let out_str: Vec<String> = out.into_iter().map(|v| format!("{}", v)).collect();
-    Ok(Some(out_str.join(",")))
+    Ok((program_ops, program, registers))
 }
 
-pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
-    let (program_ops, registers_map) = parse(input)?;
+fn format_output(out: &[u8]) -> String {
+    let out_str: Vec<String> = out.iter().map(|v| format!("{}", v)).collect();
+    out_str.join(",")
+}
 
-    let mut program = Vec::new();
-    for chunk in program_ops.chunks(2) {
-        program.push(Op::try_from((chunk[0], chunk[1])).context("failed to build op")?);
+pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<String>, anyhow::Error> {
+    let (_, program, registers) = setup(input)?;
+    let out = run_program(&program, registers)?;
+    Ok(Some(format_output(&out)))
+}
+
+// Listings for reading programs by hand, e.g. `cargo run --bin 17 -- --disassemble`
+fn print_listings(args: &Args, program_ops: &[u8], program: &[Op]) -> Result<()> {
+    if args.flag("disassemble") {
+        println!("{}", disassemble(program));
+    }
+    if args.flag("symbolic") {
+        let shift = loop_shift(program)?;
+        let output = symbolic_output(program)?;
+        for (i, expr) in digit_exprs(&output, shift, program_ops.len())
+            .iter()
+            .enumerate()
+        {
+            println!("out[{}] = {}", i, expr);
+        }
+    }
+    for path in args.values("assemble")? {
+        let listing =
+            std::fs::read_to_string(path).context(format!("failed to read listing {:?}", path))?;
+        println!("Program: {}", assemble(&listing)?);
+    }
+    Ok(())
+}
+
+/// Part one from the command line. `--trace <file>` and `--break <breakpoint>` run the
+/// program under the debugger instead, printing the registers at every breakpoint hit and
+/// writing every step to the trace file as JSON lines.
+fn cli_part_one(input: &str, run_type: RunType) -> Result<Option<String>, anyhow::Error> {
+    let args = Args::from_env();
+    let (program_ops, program, registers) = setup(input)?;
+    print_listings(&args, &program_ops, &program)?;
+
+    let trace_path = args.value("trace")?;
+    let breakpoints = args
+        .values("break")?
+        .into_iter()
+        .map(|spec| spec.parse::<Breakpoint>())
+        .collect::<Result<Vec<_>>>()?;
+    if trace_path.is_none() && breakpoints.is_empty() {
+        return part_one(input, run_type);
+    }
+
+    let mut debugger = Debugger::new(&program, registers);
+    for breakpoint in breakpoints {
+        debugger.add_breakpoint(breakpoint);
+    }
+    debugger.run_to_halt(|debugger, idx| {
+        println!(
+            "break {:?} at ip={} {:?}",
+            debugger.breakpoints[idx], debugger.ip, debugger.registers
+        );
+    })?;
+    if let Some(path) = trace_path {
+        std::fs::write(path, debugger.trace_json_lines())
+            .context(format!("failed to write trace {:?}", path))?;
+    }
+    Ok(Some(format_output(&debugger.output())))
+}
+
+/// Checks the program has the usual shape of a single loop that outputs one digit and then
+/// shifts A right by a constant before jumping back to the start. Returns the shift in bits.
+fn loop_shift(program: &[Op]) -> Result<u32> {
+    match program.last() {
+        Some(Op::Jnz(Literal(0))) => {}
+        Some(other) => {
+            return Err(anyhow!(
+                "expected program to end with jnz 0 but found {} {}",
+                other.name(),
+                other.operand_str()
+            ));
+        }
+        None => return Err(anyhow!("expected program to have at least one op")),
     }
-    let input_registers = Registers([
-        0,
-        *registers_map
-            .get(&'B')
-            .context("expected to find the B register")?,
-        *registers_map
-            .get(&'C')
-            .context("expected to find the C register")?,
-    ]);
 
-    let mut cycle: u64 = 0;
-    loop {
-        let mut regs = input_registers.clone();
-        *regs.a_mut() = cycle;
+    let mut shift = None;
+    let mut outputs = 0;
+    for op in &program[..program.len() - 1] {
+        match op {
+            Op::Adv(combo) if combo.0 > 0 && combo.0 <= 3 && shift.is_none() => {
+                shift = Some(combo.0 as u32);
+            }
+            Op::Out(_) => outputs += 1,
+            Op::Adv(_) | Op::Jnz(_) => {
+                return Err(anyhow!(
+                    "found unsupported {} {} in loop body",
+                    op.name(),
+                    op.operand_str()
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    if outputs != 1 {
+        return Err(anyhow!(
+            "expected exactly one Out per loop but found {}",
+            outputs
+        ));
+    }
+    shift.context("expected loop to shift A by a constant with Adv")
+}
 
-        let output = run_program(&program, regs);
-        if output.len() > 1 {
-            break;
+/// The value of a register or output in terms of the registers at the start of a loop
+/// iteration.
+#[derive(Debug, Clone, Ord, Eq, PartialEq, PartialOrd, Hash)]
+enum Expr {
+    A,
+    B,
+    C,
+    Const(u64),
+    Xor(Box<Expr>, Box<Expr>),
+    Mod8(Box<Expr>),
+    Shr(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn xor(left: Expr, right: Expr) -> Expr {
+        match (left, right) {
+            (Expr::Const(l), Expr::Const(r)) => Expr::Const(l ^ r),
+            (other, Expr::Const(0)) | (Expr::Const(0), other) => other,
+            // Keep constants on the right so they fold together
+            (Expr::Const(l), other) => Expr::xor(other, Expr::Const(l)),
+            (Expr::Xor(inner, l), Expr::Const(r)) => match *l {
+                Expr::Const(l) => Expr::xor(*inner, Expr::Const(l ^ r)),
+                l => Expr::Xor(
+                    Box::new(Expr::Xor(inner, Box::new(l))),
+                    Box::new(Expr::Const(r)),
+                ),
+            },
+            (left, right) => Expr::Xor(Box::new(left), Box::new(right)),
         }
-        cycle += 1;
     }
 
-    let mut target_digit = program_ops.len() - 1;
-    let mut possible_a = cycle.pow(target_digit as u32);
-    loop {
-        let mut regs = input_registers.clone();
-        *regs.a_mut() = possible_a;
+    fn mod8(inner: Expr) -> Expr {
+        match inner {
+            Expr::Const(v) => Expr::Const(v % 8),
+            Expr::Mod8(_) => inner,
+            other => Expr::Mod8(Box::new(other)),
+        }
+    }
 
-        let output = run_program(&program, regs);
-        println!("{:?} = {}", output, possible_a);
+    fn shr(value: Expr, amount: Expr) -> Expr {
+        match (value, amount) {
+            (value, Expr::Const(0)) => value,
+            (Expr::Const(v), Expr::Const(a)) if a < 64 => Expr::Const(v >> a),
+            (Expr::Shr(inner, first), Expr::Const(second)) => match *first {
+                Expr::Const(first) => Expr::shr(*inner, Expr::Const(first.saturating_add(second))),
+                first => Expr::Shr(
+                    Box::new(Expr::Shr(inner, Box::new(first))),
+                    Box::new(Expr::Const(second)),
+                ),
+            },
+            (value, amount) => Expr::Shr(Box::new(value), Box::new(amount)),
+        }
+    }
 
-        if output == program_ops {
-            break;
+    fn combo(combo: &Combo, registers: &[Expr; 3], ip: u64) -> Result<Expr, VmError> {
+        Ok(match combo.0 {
+            0..=3 => Expr::Const(combo.0 as u64),
+            4 => registers[0].clone(),
+            5 => registers[1].clone(),
+            6 => registers[2].clone(),
+            _ => return Err(VmError::InvalidCombo(ip)),
+        })
+    }
+
+    fn uses_register(&self, register: &Expr) -> bool {
+        match self {
+            Expr::Const(_) => false,
+            Expr::A | Expr::B | Expr::C => self == register,
+            Expr::Mod8(inner) => inner.uses_register(register),
+            Expr::Xor(l, r) | Expr::Shr(l, r) => {
+                l.uses_register(register) || r.uses_register(register)
+            }
         }
+    }
 
-        if output[target_digit] == program_ops[target_digit] {
-            println!(
-                "HERE: {} stepping by {}",
-                target_digit,
-                cycle.pow(target_digit as u32)
-            );
-            if target_digit == 0 {
-                break;
+    /// Replaces A with `a`, rebuilding so that constants fold again
+    fn substitute_a(&self, a: &Expr) -> Expr {
+        match self {
+            Expr::A => a.clone(),
+            Expr::B | Expr::C | Expr::Const(_) => self.clone(),
+            Expr::Xor(l, r) => Expr::xor(l.substitute_a(a), r.substitute_a(a)),
+            Expr::Mod8(inner) => Expr::mod8(inner.substitute_a(a)),
+            Expr::Shr(l, r) => Expr::shr(l.substitute_a(a), r.substitute_a(a)),
+        }
+    }
+
+    /// Returns None when a shift doesn't fit in a u64, which the VM treats as an overflow
+    fn eval(&self, a: u64, b: u64, c: u64) -> Option<u64> {
+        Some(match self {
+            Expr::A => a,
+            Expr::B => b,
+            Expr::C => c,
+            Expr::Const(v) => *v,
+            Expr::Xor(l, r) => l.eval(a, b, c)? ^ r.eval(a, b, c)?,
+            Expr::Mod8(inner) => inner.eval(a, b, c)? % 8,
+            Expr::Shr(l, r) => {
+                let amount = u32::try_from(r.eval(a, b, c)?).ok()?;
+                if amount >= 64 {
+                    return None;
+                }
+                l.eval(a, b, c)? >> amount
+            }
+        })
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Nested operators get brackets so the precedence is never in doubt
+        let nested = |f: &mut std::fmt::Formatter<'_>, e: &Expr| match e {
+            Expr::Xor(..) | Expr::Mod8(_) | Expr::Shr(..) => write!(f, "({})", e),
+            _ => write!(f, "{}", e),
+        };
+        match self {
+            Expr::A => write!(f, "A"),
+            Expr::B => write!(f, "B"),
+            Expr::C => write!(f, "C"),
+            Expr::Const(v) => write!(f, "{}", v),
+            Expr::Xor(l, r) => {
+                // Xor is associative so chains of them don't need brackets
+                match **l {
+                    Expr::Xor(..) => write!(f, "{}", l)?,
+                    _ => nested(f, l)?,
+                }
+                write!(f, " ^ ")?;
+                nested(f, r)
+            }
+            Expr::Mod8(inner) => {
+                nested(f, inner)?;
+                write!(f, " % 8")
+            }
+            Expr::Shr(l, r) => {
+                nested(f, l)?;
+                write!(f, " >> ")?;
+                nested(f, r)
             }
-            target_digit -= 1;
-        } else {
-            possible_a += cycle.pow(target_digit as u32);
         }
     }
+}
 
-    for i in 0..100000 {
-        let mut regs = input_registers.clone();
-        *regs.a_mut() = possible_a + i;
-        let output = run_program(&program, regs);
-        println!("{:?} = {}", output, possible_a + i);
+/// Symbolically runs one iteration of a simple output loop (see `loop_shift`) and returns
+/// the digit it outputs in terms of A at the start of the iteration. Fails if the output
+/// depends on B or C carried over from the previous iteration.
+fn symbolic_output(program: &[Op]) -> Result<Expr> {
+    loop_shift(program).context("program is not a simple output loop")?;
 
-        if output == program_ops {
-            println!("I={}", i);
-            break;
+    let mut registers = [Expr::A, Expr::B, Expr::C];
+    let mut output = None;
+    for (ip, op) in program[..program.len() - 1].iter().enumerate() {
+        let ip = ip as u64;
+        match op {
+            Op::Adv(combo) => {
+                registers[0] = Expr::shr(registers[0].clone(), Expr::combo(combo, &registers, ip)?);
+            }
+            Op::Bxl(literal) => {
+                registers[1] = Expr::xor(registers[1].clone(), Expr::Const(literal.0 as u64));
+            }
+            Op::Bst(combo) => {
+                registers[1] = Expr::mod8(Expr::combo(combo, &registers, ip)?);
+            }
+            Op::Jnz(_) => unreachable!("loop_shift only allows a final jnz"),
+            Op::Bxc(_) => {
+                registers[1] = Expr::xor(registers[1].clone(), registers[2].clone());
+            }
+            Op::Out(combo) => {
+                output = Some(Expr::mod8(Expr::combo(combo, &registers, ip)?));
+            }
+            Op::Bdb(combo) => {
+                registers[1] = Expr::shr(registers[0].clone(), Expr::combo(combo, &registers, ip)?);
+            }
+            Op::Cdv(combo) => {
+                registers[2] = Expr::shr(registers[0].clone(), Expr::combo(combo, &registers, ip)?);
+            }
         }
     }
 
-    for op in program {
-        println!("{} {}", op.name(), op.operand_str());
+    let output = output.context("expected loop to output a digit")?;
+    if output.uses_register(&Expr::B) || output.uses_register(&Expr::C) {
+        return Err(anyhow!(
+            "output {} depends on registers from the previous iteration",
+            output
+        ));
     }
+    Ok(output)
+}
 
-    // println!("HERE: {}", i);
+/// The closed form of every digit the program outputs, digit `i` sees A shifted right by
+/// `shift * i` bits.
+fn digit_exprs(output: &Expr, shift: u32, digits: usize) -> Vec<Expr> {
+    (0..digits)
+        .map(|i| output.substitute_a(&Expr::shr(Expr::A, Expr::Const(shift as u64 * i as u64))))
+        .collect()
+}
 
+/// Each loop iteration consumes the low `shift` bits of A and outputs one digit, so the last
+/// digit only depends on the highest bits of A. We build A up from the last digit backwards,
+/// trying every value for the next `shift` bits and keeping those where `reproduces` says the
+/// tail of the program from `digit` onwards is output. Trying the bits in ascending order means
+/// the first match is the smallest.
+fn find_quine<F, E>(shift: u32, a: u64, digit: usize, reproduces: &F) -> Result<Option<u64>, E>
+where
+    F: Fn(u64, usize) -> Result<bool, E>,
+{
+    // Programs too long for A to fit in a u64
+    if a.leading_zeros() < shift {
+        return Ok(None);
+    }
+    for bits in 0..(1 << shift) {
+        let candidate = (a << shift) | bits;
+        if !reproduces(candidate, digit)? {
+            continue;
+        }
+        if digit == 0 {
+            return Ok(Some(candidate));
+        }
+        if let Some(found) = find_quine(shift, candidate, digit - 1, reproduces)? {
+            return Ok(Some(found));
+        }
+    }
     Ok(None)
 }
 
+fn find_quine_vm(
+    program: &[Op],
+    program_ops: &[u8],
+    registers: &Registers,
+    shift: u32,
+) -> Result<Option<u64>, VmError> {
+    find_quine(shift, 0, program_ops.len() - 1, &|candidate, digit| {
+        let mut regs = registers.clone();
+        *regs.a_mut() = candidate;
+        Ok(run_program(program, regs)? == program_ops[digit..])
+    })
+}
+
+// The tail after `digit` was already checked for `candidate >> shift`, so only the new
+// digit needs evaluating, as long as the loop doesn't stop early because A ran out.
+fn find_quine_symbolic(output: &Expr, program_ops: &[u8], shift: u32) -> Result<Option<u64>> {
+    let last_digit = program_ops.len() - 1;
+    find_quine(shift, 0, last_digit, &|candidate, digit| {
+        if digit != last_digit && candidate >> shift == 0 {
+            return Ok(false);
+        }
+        let value = output.eval(candidate, 0, 0).context(format!(
+            "output for A={} shifts by more than 63 bits",
+            candidate
+        ))?;
+        Ok(value == program_ops[digit] as u64)
+    })
+}
+
+pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
+    let (program_ops, registers_map) = parse(input)?;
+    let program = build_program(&program_ops)?;
+
+    let input_registers = Registers([
+        0,
+        *registers_map
+            .get(&'B')
+            .context("expected to find the B register")?,
+        *registers_map
+            .get(&'C')
+            .context("expected to find the C register")?,
+    ]);
+
+    let shift = loop_shift(&program).context("program is not a simple output loop")?;
+
+    // Skip the VM when the output has a closed form
+    let found = match symbolic_output(&program) {
+        Ok(output) => find_quine_symbolic(&output, &program_ops, shift)?,
+        Err(_) => find_quine_vm(&program, &program_ops, &input_registers, shift)?,
+    };
+    let a = found.context("no value of A makes the program output itself")?;
+
+    Ok(Some(a))
+}
+
 #[cfg(test)]
 mod tests_day_17 {
     use super::*;
@@ -346,4 +996,210 @@ mod tests_day_17 {
         assert_eq!(result, expected);
         Ok(())
     }
+
+    #[test]
+    fn test_part_two_real_shape() -> anyhow::Result<()> {
+        let input = "Register A: 0\nRegister B: 0\nRegister C: 0\n\nProgram: 2,4,1,1,7,5,1,5,4,0,5,5,0,3,3,0\n";
+        let a = part_two(input, RunType::Example)?.context("expected an answer")?;
+
+        let replaced = input.replacen("Register A: 0", &format!("Register A: {}", a), 1);
+        let result = part_one(&replaced, RunType::Example)?;
+        assert_eq!(result, Some("2,4,1,1,7,5,1,5,4,0,5,5,0,3,3,0".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_part_two_no_loop() {
+        let input = "Register A: 0\nRegister B: 0\nRegister C: 0\n\nProgram: 0,3,5,4\n";
+        assert!(part_two(input, RunType::Example).is_err());
+    }
+
+    #[test]
+    fn test_disassemble_round_trip() -> anyhow::Result<()> {
+        let program_str = "2,4,1,1,7,5,1,5,4,0,5,5,0,3,3,0";
+        let program_ops: Vec<u8> = program_str.split(',').map(|v| v.parse().unwrap()).collect();
+        let program = build_program(&program_ops)?;
+
+        let listing = disassemble(&program);
+        assert_eq!(
+            listing.lines().collect::<Vec<_>>(),
+            vec![
+                "  0: bst %A",
+                "  1: bxl 1",
+                "  2: cdv %B",
+                "  3: bxl 5",
+                "  4: bxc",
+                "  5: out %B",
+                "  6: adv 3",
+                "  7: jnz 0 ; -> 0: bst %A",
+            ]
+        );
+        assert_eq!(assemble(&listing)?, program_str);
+
+        // bxc ignores its operand but it still has to survive the round trip
+        let program_str = "2,4,1,5,7,5,1,6,0,3,4,6,5,5,3,0";
+        let program_ops: Vec<u8> = program_str.split(',').map(|v| v.parse().unwrap()).collect();
+        let listing = disassemble(&build_program(&program_ops)?);
+        assert!(listing.contains("  5: bxc 6"));
+        assert_eq!(assemble(&listing)?, program_str);
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble() -> anyhow::Result<()> {
+        assert_eq!(assemble("adv 1\nout %A\njnz 0\n")?, "0,1,5,4,3,0");
+        assert!(assemble("adv %D").is_err());
+        assert!(assemble("bxl 8").is_err());
+        assert!(assemble("nop 1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_debugger() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let (program_ops, _) = parse(input)?;
+        let program = build_program(&program_ops)?;
+
+        // Breakpoints fire on entry and continuing only skips the one just reported
+        let mut debugger = Debugger::new(&program, Registers([729, 0, 0]));
+        debugger.add_breakpoint("ip=0".parse()?);
+        assert_eq!(debugger.run()?, Stop::Breakpoint(0));
+        assert_eq!((debugger.ip, debugger.registers.a()), (0, 729));
+        assert!(debugger.trace.is_empty());
+        assert_eq!(debugger.run()?, Stop::Breakpoint(0));
+        assert_eq!((debugger.ip, debugger.registers.a()), (0, 364));
+        assert_eq!(debugger.trace.len(), 3);
+
+        let mut debugger = Debugger::new(&program, Registers([729, 0, 0]));
+        debugger.add_breakpoint("ip=1".parse()?);
+        debugger.add_breakpoint("A<10".parse()?);
+
+        assert_eq!(debugger.run()?, Stop::Breakpoint(0));
+        assert_eq!(debugger.ip, 1);
+        assert_eq!(debugger.registers.a(), 364);
+
+        let step = debugger.step()?.context("expected to step")?.clone();
+        assert_eq!(step.op, Op::Out(Combo(4)));
+        assert_eq!(step.output, Some(4));
+        assert_eq!(
+            step.to_json(),
+            r#"{"ip":1,"op":"out %A","before":[364,0,0],"after":[364,0,0],"output":4}"#
+        );
+
+        // The next loop iteration hits the same ip again
+        assert_eq!(debugger.run()?, Stop::Breakpoint(0));
+        assert_eq!(debugger.registers.a(), 182);
+
+        debugger.breakpoints.remove(0);
+        assert_eq!(debugger.run()?, Stop::Breakpoint(0));
+        assert!(debugger.registers.a() < 10);
+
+        // A stays small from here on so every remaining step breaks
+        while debugger.run()? != Stop::Halted {
+            assert!(debugger.registers.a() < 10);
+        }
+        assert_eq!(
+            debugger.output(),
+            run_program(&program, Registers([729, 0, 0]))?
+        );
+        assert_eq!(
+            debugger.trace_json_lines().lines().count(),
+            debugger.trace.len()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_faults() -> anyhow::Result<()> {
+        let registers = Registers([10, 0, 0]);
+
+        // jnz 0 forever
+        let program = build_program(&[3, 0])?;
+        assert_eq!(
+            run_program(&program, registers.clone()),
+            Err(VmError::StepLimit(STEP_LIMIT))
+        );
+
+        // out 7
+        let program = build_program(&[5, 7])?;
+        assert_eq!(
+            run_program(&program, registers.clone()),
+            Err(VmError::InvalidCombo(0))
+        );
+
+        // bxl 0; adv %A with A=64
+        let program = build_program(&[1, 0, 0, 4])?;
+        assert_eq!(
+            run_program(&program, Registers([64, 0, 0])),
+            Err(VmError::Overflow(1, 64))
+        );
+        assert_eq!(run_program(&program, Registers([63, 0, 0])), Ok(Vec::new()));
+
+        // bxl 1; jnz 0 forever, breaking every time B is 1
+        let program = build_program(&[1, 1, 3, 0])?;
+        let mut debugger = Debugger::new(&program, registers);
+        debugger.add_breakpoint("B=1".parse()?);
+        let mut hits = 0;
+        assert_eq!(
+            debugger.run_to_halt(|_, _| hits += 1),
+            Err(VmError::StepLimit(STEP_LIMIT))
+        );
+        assert_eq!(hits, STEP_LIMIT / 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbolic() -> anyhow::Result<()> {
+        let program_ops = vec![2, 4, 1, 1, 7, 5, 1, 5, 4, 0, 5, 5, 0, 3, 3, 0];
+        let program = build_program(&program_ops)?;
+        let output = symbolic_output(&program)?;
+        assert_eq!(
+            format!("{}", output),
+            "((A % 8) ^ 4 ^ (A >> ((A % 8) ^ 1))) % 8"
+        );
+
+        let digits = digit_exprs(&output, 3, 2);
+        assert_eq!(
+            format!("{}", digits[1]),
+            "(((A >> 3) % 8) ^ 4 ^ ((A >> 3) >> (((A >> 3) % 8) ^ 1))) % 8"
+        );
+
+        for a in [0, 1, 7, 8, 117440, 164279024971453] {
+            let vm = run_program(&program, Registers([a, 0, 0]))?;
+            let symbolic: Vec<u8> = digits
+                .iter()
+                .take(vm.len())
+                .map(|d| d.eval(a, 0, 0).unwrap() as u8)
+                .collect();
+            assert_eq!(symbolic, vm[..symbolic.len()]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbolic_matches_vm_search() -> anyhow::Result<()> {
+        let examples = [
+            vec![0, 3, 5, 4, 3, 0],
+            vec![2, 4, 1, 1, 7, 5, 1, 5, 4, 0, 5, 5, 0, 3, 3, 0],
+            vec![2, 4, 1, 3, 7, 5, 0, 3, 1, 5, 4, 4, 5, 5, 3, 0],
+        ];
+        for program_ops in examples {
+            let program = build_program(&program_ops)?;
+            let shift = loop_shift(&program)?;
+            let output = symbolic_output(&program)?;
+            assert_eq!(
+                find_quine_symbolic(&output, &program_ops, shift)?,
+                find_quine_vm(&program, &program_ops, &Registers([0, 0, 0]), shift)?,
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbolic_carried_register() -> anyhow::Result<()> {
+        // out %B without setting B first depends on the previous iteration
+        let program = build_program(&[0, 3, 5, 5, 3, 0])?;
+        assert!(symbolic_output(&program).is_err());
+        Ok(())
+    }
 }
@@ -1,68 +1,159 @@
-advent_of_code::solution!(18);
+advent_of_code::solution!(@impl 18, [cli_part_one, 1] [cli_part_two, 2]);
 
 use advent_of_code::template::RunType;
+use aoc_solutions::cli::Args;
 
 use aoc_lib::grid::{Direction, Location, Map, UnboundLocation};
 use aoc_lib::parse::preamble::*;
 
-use anyhow::{Context, Result};
-use core::cmp::Reverse;
-use std::collections::BinaryHeap;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeSet, VecDeque};
 
-fn seek(map: &Map<bool>, start: &Location, end: &Location) -> Option<usize> {
-    let mut to_visit = BinaryHeap::new();
-    to_visit.push((Reverse(0), 0, end.clone()));
-
-    let mut seen = map.transform(|_, _| None);
+/// Breadth first search from `start`, remembering where each cell was first reached from so
+/// the shortest route can be walked back from `end`. The route includes both ends.
+fn seek(map: &Map<bool>, start: &Location, end: &Location) -> Option<Vec<Location>> {
+    if *map.get(start) || *map.get(end) {
+        return None;
+    }
 
-    while !to_visit.is_empty() {
-        let (_, dist, current) = to_visit.pop().unwrap();
-        if let Some(existing_dist) = seen.get(&current) {
-            if existing_dist <= &dist {
-                continue;
-            }
-        }
-        *seen.get_mut(&current) = Some(dist);
-        if &current == start {
+    let mut came_from: Map<Option<Location>> = map.transform(|_, _| None);
+    let mut to_visit = VecDeque::from([start.clone()]);
+    while let Some(current) = to_visit.pop_front() {
+        if &current == end {
             break;
         }
-
         for direction in Direction::all() {
             if let Some(next) = map.go_direction(&current, direction) {
-                if !map.get(&next) {
-                    to_visit.push((Reverse(dist), dist + 1, next.clone()))
+                if !map.get(&next) && &next != start && came_from.get(&next).is_none() {
+                    *came_from.get_mut(&next) = Some(current.clone());
+                    to_visit.push_back(next);
                 }
             }
         }
     }
 
-    *seen.get(start)
+    let mut path = vec![end.clone()];
+    while path.last() != Some(start) {
+        let prev = came_from.get(path.last().unwrap()).clone()?;
+        path.push(prev);
+    }
+    path.reverse();
+    Some(path)
+}
+
+/// Draws the memory space like the puzzle does, `#` for corrupted bytes and `O` for the path.
+fn render(map: &Map<bool>, path: &[Location]) -> String {
+    let path: BTreeSet<&Location> = path.iter().collect();
+    let mut out = String::new();
+    for row in map.iter() {
+        for (loc, corrupted) in row {
+            out.push(if *corrupted {
+                '#'
+            } else if path.contains(&loc) {
+                'O'
+            } else {
+                '.'
+            });
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// The size of the memory space and how many bytes to drop for part one.
+#[derive(Debug, Clone, PartialEq)]
+struct Space {
+    width: usize,
+    height: usize,
+    drop: usize,
+}
+
+impl Space {
+    /// Starts from the puzzle's sizes, then applies any `key: value` header lines at the top
+    /// of the input. `size` sets both the width and height and `bytes` is how many bytes
+    /// fall in part one. Returns the input with the headers stripped off.
+    fn configure(input: &str, run_type: RunType) -> Result<(Self, &str)> {
+        let mut space = match run_type {
+            RunType::Example => Self {
+                width: 7,
+                height: 7,
+                drop: 12,
+            },
+            RunType::Real => Self {
+                width: 71,
+                height: 71,
+                drop: 1024,
+            },
+        };
+
+        let mut body = 0;
+        for line in input.split_inclusive('\n') {
+            let Some((key, value)) = line.split_once(':') else {
+                break;
+            };
+            space.set(key.trim(), value.trim())?;
+            body += line.len();
+        }
+        Ok((space, &input[body..]))
+    }
+
+    /// Applies `--size`, `--width`, `--height` and `--bytes` from the command line on top,
+    /// with `--width` and `--height` winning over `--size`.
+    fn override_with(&mut self, args: &Args) -> Result<()> {
+        for key in ["size", "width", "height", "bytes"] {
+            if let Some(value) = args.value(key)? {
+                self.set(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let value: usize = value
+            .parse()
+            .context(format!("failed to parse {} value {:?}", key, value))?;
+        if value == 0 && key != "bytes" {
+            return Err(anyhow!("memory space {} can't be 0", key));
+        }
+        match key {
+            "width" => self.width = value,
+            "height" => self.height = value,
+            "size" => {
+                self.width = value;
+                self.height = value;
+            }
+            "bytes" => self.drop = value,
+            _ => return Err(anyhow!("unknown memory space setting {:?}", key)),
+        }
+        Ok(())
+    }
+
+    fn map(&self) -> Map<bool> {
+        Map::from_dimensions(self.height, self.width, |_| false)
+    }
 }
 
-fn parse(input: &str, dim: usize) -> Result<(Vec<Location>, Location, Location)> {
+fn parse(input: &str, space: &Space) -> Result<(Vec<Location>, Location, Location)> {
     let data: Vec<(i64, i64)> = parse_input(
         LineSplitter,
         ParseTuple2(ParseFromStr, ParseFromStr, ","),
         input,
     )
     .context("failed to parse input")?;
-    let map = Map::from_dimensions(dim, dim, |_| false);
+    let map = space.map();
 
     let mut blocks = Vec::new();
-    for (x, y) in data {
-        let loc = UnboundLocation(y, x)
-            .to_bounded(&map)
-            .context("expected to build real location")?;
+    for (idx, (x, y)) in data.into_iter().enumerate() {
+        let loc = UnboundLocation(y, x).to_bounded(&map).context(format!(
+            "byte {} at {},{} is outside the {}x{} memory space",
+            idx, x, y, space.width, space.height
+        ))?;
 
         blocks.push(loc);
     }
 
-    let start = UnboundLocation(0, 0)
-        .to_bounded(&map)
-        .context("expected to build real location")?;
-    let end = UnboundLocation(dim as i64 - 1, dim as i64 - 1)
-        .to_bounded(&map)
-        .context("expected to build real location")?;
+    let start = Location(0, 0);
+    let end = Location(space.height - 1, space.width - 1);
 
     Ok((blocks, start, end))
 }
@@ -73,46 +164,241 @@ fn mark<'a, I: Iterator<Item = &'a Location>>(map: &'a mut Map<bool>, blocks: I)
     }
 }
 
+/// The shortest route out once the first `space.drop` bytes have fallen, along with the
+/// map of fallen bytes it was found on.
+fn route(space: &Space, input: &str) -> Result<(Map<bool>, Vec<Location>)> {
+    let (blocks, start, end) = parse(input, space).context("failed to parse input")?;
+
+    let mut map = space.map();
+    mark(&mut map, blocks.iter().take(space.drop));
+
+    let path = seek(&map, &start, &end).context("expected to find route to exist")?;
+    Ok((map, path))
+}
+
 pub fn part_one(input: &str, run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
-    let (dim, drop) = match run_type {
-        RunType::Example => (7, 12),
-        RunType::Real => (71, 1024),
-    };
-    let (blocks, start, end) = parse(input, dim).context("failed to parse input")?;
+    let (space, input) = Space::configure(input, run_type)?;
+    let (_, path) = route(&space, input)?;
+    Ok(Some(path.len() - 1))
+}
 
-    let mut map = Map::from_dimensions(dim, dim, |_| false);
-    mark(&mut map, blocks.iter().take(drop));
+/// Part one from the command line. `--show` draws the route like the puzzle does.
+fn cli_part_one(input: &str, run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
+    let args = Args::from_env();
+    let (mut space, input) = Space::configure(input, run_type)?;
+    space.override_with(&args)?;
+    let (map, path) = route(&space, input)?;
+    if args.flag("show") {
+        print!("{}", render(&map, &path));
+    }
+    Ok(Some(path.len() - 1))
+}
 
-    let dist = seek(&map, &start, &end).context("expected to find route to exist")?;
+struct UnionFind {
+    parent: Vec<usize>,
+    size: Vec<usize>,
+}
 
-    Ok(Some(dist))
+impl UnionFind {
+    fn new(len: usize) -> Self {
+        Self {
+            parent: (0..len).collect(),
+            size: vec![1; len],
+        }
+    }
+
+    fn find(&mut self, mut idx: usize) -> usize {
+        while self.parent[idx] != idx {
+            // Path halving, point at our grandparent as we walk up
+            self.parent[idx] = self.parent[self.parent[idx]];
+            idx = self.parent[idx];
+        }
+        idx
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        let (big, small) = if self.size[a] >= self.size[b] {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        self.parent[small] = big;
+        self.size[big] += self.size[small];
+    }
+
+    fn connected(&mut self, a: usize, b: usize) -> bool {
+        self.find(a) == self.find(b)
+    }
 }
 
-pub fn part_two(input: &str, run_type: RunType) -> Result<Option<String>, anyhow::Error> {
-    let dim = match run_type {
-        RunType::Example => 7,
-        RunType::Real => 71,
+fn cell(width: usize, loc: &Location) -> usize {
+    loc.0 * width + loc.1
+}
+
+/// The index of the first byte that cuts `start` off from `end`, or None if they stay
+/// connected. Starts with every byte fallen and removes them newest first, joining each freed
+/// cell to its free neighbours, so the byte that reconnects them is the one that cut them.
+fn first_blocking(
+    space: &Space,
+    blocks: &[Location],
+    start: &Location,
+    end: &Location,
+) -> Option<usize> {
+    let width = space.width;
+    let mut fallen = vec![0; width * space.height];
+    for block in blocks {
+        fallen[cell(width, block)] += 1;
+    }
+
+    let map = space.map();
+    let mut sets = UnionFind::new(width * space.height);
+    let free = |sets: &mut UnionFind, fallen: &[usize], loc: &Location| {
+        for direction in Direction::all() {
+            if let Some(next) = map.go_direction(loc, direction) {
+                if fallen[cell(width, &next)] == 0 {
+                    sets.union(cell(width, loc), cell(width, &next));
+                }
+            }
+        }
     };
-    let (blocks, start, end) = parse(input, dim).context("failed to parse input")?;
-
-    let mut left = 0;
-    let mut right = blocks.len();
-    while left != right && left + 1 != right {
-        let mid = left + ((right - left) / 2);
-        let mut map = Map::from_dimensions(dim, dim, |_| false);
-        mark(&mut map, blocks.iter().take(mid));
-
-        let can_route = seek(&map, &start, &end).is_some();
-        if can_route {
-            left = mid;
-        } else {
-            right = mid;
+
+    for row in 0..space.height {
+        for col in 0..width {
+            let loc = Location(row, col);
+            if fallen[cell(width, &loc)] == 0 {
+                free(&mut sets, &fallen, &loc);
+            }
+        }
+    }
+    let connected = |sets: &mut UnionFind, fallen: &[usize]| {
+        fallen[cell(width, start)] == 0
+            && fallen[cell(width, end)] == 0
+            && sets.connected(cell(width, start), cell(width, end))
+    };
+    if connected(&mut sets, &fallen) {
+        return None;
+    }
+
+    for (idx, block) in blocks.iter().enumerate().rev() {
+        // The same byte can fall more than once, only the first time counts
+        fallen[cell(width, block)] -= 1;
+        if fallen[cell(width, block)] != 0 {
+            continue;
+        }
+        free(&mut sets, &fallen, block);
+        if connected(&mut sets, &fallen) {
+            return Some(idx);
         }
     }
+    None
+}
+
+/// Tracks bytes one at a time as they fall. With the start and end in opposite corners the
+/// way between them is cut exactly when fallen bytes, touching diagonally included, join the
+/// top or right edge to the left or bottom edge, so we only ever need to union bytes.
+struct Corruption {
+    width: usize,
+    height: usize,
+    fallen: Vec<bool>,
+    sets: UnionFind,
+    corner_hit: bool,
+}
+
+impl Corruption {
+    fn new(space: &Space) -> Self {
+        let cells = space.width * space.height;
+        Self {
+            width: space.width,
+            height: space.height,
+            fallen: vec![false; cells],
+            // Plus one set for the top/right edge and one for the left/bottom edge
+            sets: UnionFind::new(cells + 2),
+            corner_hit: false,
+        }
+    }
+
+    fn top_right(&self) -> usize {
+        self.width * self.height
+    }
+
+    fn bottom_left(&self) -> usize {
+        self.width * self.height + 1
+    }
+
+    /// Drops a byte on `loc` and returns whether the start and end are still connected.
+    fn add(&mut self, loc: &Location) -> bool {
+        let (width, height) = (self.width, self.height);
+        let idx = cell(width, loc);
+        if !self.fallen[idx] {
+            self.fallen[idx] = true;
+
+            let Location(row, col) = *loc;
+            if (row, col) == (0, 0) || (row, col) == (height - 1, width - 1) {
+                self.corner_hit = true;
+            }
+            if row == 0 || col == width - 1 {
+                self.sets.union(idx, self.top_right());
+            }
+            if row == height - 1 || col == 0 {
+                self.sets.union(idx, self.bottom_left());
+            }
+            for d_row in -1..=1 {
+                for d_col in -1..=1 {
+                    let (n_row, n_col) = (row as i64 + d_row, col as i64 + d_col);
+                    if n_row < 0 || n_col < 0 || n_row >= height as i64 || n_col >= width as i64 {
+                        continue;
+                    }
+                    let next = cell(width, &Location(n_row as usize, n_col as usize));
+                    if self.fallen[next] {
+                        self.sets.union(idx, next);
+                    }
+                }
+            }
+        }
+        self.connected()
+    }
 
-    let block = &blocks[left];
+    fn connected(&mut self) -> bool {
+        let (top_right, bottom_left) = (self.top_right(), self.bottom_left());
+        !self.corner_hit && !self.sets.connected(top_right, bottom_left)
+    }
+}
 
-    Ok(Some(format!("{},{}", block.1, block.0)))
+fn blocking_byte(space: &Space, input: &str) -> Result<String> {
+    let (blocks, start, end) = parse(input, space).context("failed to parse input")?;
+    let idx = first_blocking(space, &blocks, &start, &end).context("no byte blocks the exit")?;
+    let block = &blocks[idx];
+    Ok(format!("{},{}", block.1, block.0))
+}
+
+pub fn part_two(input: &str, run_type: RunType) -> Result<Option<String>, anyhow::Error> {
+    let (space, input) = Space::configure(input, run_type)?;
+    Ok(Some(blocking_byte(&space, input)?))
+}
+
+/// Part two from the command line. `--animate` prints whether the way out is still open
+/// after each byte falls, to watch it get cut off.
+fn cli_part_two(input: &str, run_type: RunType) -> Result<Option<String>, anyhow::Error> {
+    let args = Args::from_env();
+    let (mut space, input) = Space::configure(input, run_type)?;
+    space.override_with(&args)?;
+    if args.flag("animate") {
+        let (blocks, _, _) = parse(input, &space).context("failed to parse input")?;
+        let mut corruption = Corruption::new(&space);
+        for block in &blocks {
+            let state = if corruption.add(block) {
+                "connected"
+            } else {
+                "blocked"
+            };
+            println!("{},{} {}", block.1, block.0, state);
+        }
+    }
+    Ok(Some(blocking_byte(&space, input)?))
 }
 
 #[cfg(test)]
@@ -138,4 +424,180 @@ mod tests_day_18 {
         assert_eq!(result, expected);
         Ok(())
     }
+
+    #[test]
+    fn test_render() -> anyhow::Result<()> {
+        // The route drawn in the puzzle, ours may take a different turn but is just as short
+        let puzzle = "\
+OO.#OOO
+.O#OO#O
+.OOO#OO
+...#OO#
+..#OO#.
+.#.O#..
+#.#OOOO
+";
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let space = space(7, 7);
+        let (blocks, start, end) = parse(input, &space)?;
+        let mut map = space.map();
+        mark(&mut map, blocks.iter().take(12));
+
+        let path = seek(&map, &start, &end).unwrap();
+        assert_eq!(path.len(), 23);
+        assert_eq!((path.first(), path.last()), (Some(&start), Some(&end)));
+        for pair in path.windows(2) {
+            assert_eq!(pair[0].manhattan_dist(&pair[1]), 1);
+            assert!(!map.get(&pair[1]));
+        }
+
+        let drawn = render(&map, &path);
+        assert_eq!(drawn.matches('O').count(), 23);
+        for (ours, theirs) in drawn.chars().zip(puzzle.chars()) {
+            assert_eq!(ours == '#', theirs == '#');
+        }
+        let puzzle_path: Vec<Location> = puzzle
+            .lines()
+            .enumerate()
+            .flat_map(|(row, line)| {
+                line.match_indices('O')
+                    .map(move |(col, _)| Location(row, col))
+            })
+            .collect();
+        assert_eq!(puzzle_path.len(), path.len());
+        assert_eq!(render(&map, &puzzle_path), puzzle);
+
+        // A blocked end has no route at all
+        mark(&mut map, [&end].into_iter());
+        assert_eq!(seek(&map, &start, &end), None);
+        assert_eq!(render(&map, &[]).matches('#').count(), 13);
+        Ok(())
+    }
+
+    fn space(width: usize, height: usize) -> Space {
+        Space {
+            width,
+            height,
+            drop: 0,
+        }
+    }
+
+    #[test]
+    fn test_corruption_online() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 2);
+        let example = space(7, 7);
+        let (blocks, start, end) = parse(input, &example)?;
+        let blocking = first_blocking(&example, &blocks, &start, &end);
+        assert_eq!(blocking, Some(20));
+
+        // The online answer after every byte agrees with searching the map from scratch
+        let mut corruption = Corruption::new(&example);
+        let mut map = example.map();
+        for (idx, block) in blocks.iter().enumerate() {
+            mark(&mut map, [block].into_iter());
+            let connected = corruption.add(block);
+            assert_eq!(
+                connected,
+                seek(&map, &start, &end).is_some(),
+                "byte {}",
+                idx
+            );
+            assert_eq!(connected, idx < 20, "byte {}", idx);
+        }
+
+        // Dropping a byte on the exit cuts it off straight away
+        let mut corruption = Corruption::new(&example);
+        assert!(!corruption.add(&Location(6, 6)));
+        assert_eq!(
+            first_blocking(&example, &[Location(6, 6)], &start, &end),
+            Some(0)
+        );
+        assert_eq!(
+            first_blocking(&example, &[Location(3, 3)], &start, &end),
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_corruption_random() {
+        let mut state: u64 = 0x9e3779b97f4a7c15;
+        let mut next = |below: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % below as u64) as usize
+        };
+
+        // Bytes scattered over grids of a few shapes, checked against a full search
+        for (width, height) in [(15, 15), (20, 9), (6, 17), (1, 5)] {
+            let space = space(width, height);
+            let (start, end) = (Location(0, 0), Location(height - 1, width - 1));
+            let blocks: Vec<Location> = (0..width * height)
+                .map(|_| Location(next(height), next(width)))
+                .collect();
+
+            let mut corruption = Corruption::new(&space);
+            let mut map = space.map();
+            let mut blocked_at = None;
+            for (idx, block) in blocks.iter().enumerate() {
+                mark(&mut map, [block].into_iter());
+                let connected = corruption.add(block);
+                assert_eq!(
+                    connected,
+                    seek(&map, &start, &end).is_some(),
+                    "{}x{} byte {}",
+                    width,
+                    height,
+                    idx
+                );
+                if !connected && blocked_at.is_none() {
+                    blocked_at = Some(idx);
+                }
+            }
+            assert!(blocked_at.is_some());
+            assert_eq!(first_blocking(&space, &blocks, &start, &end), blocked_at);
+        }
+    }
+
+    #[test]
+    fn test_space_headers() -> anyhow::Result<()> {
+        let input = "width: 3\nheight: 2\nbytes: 1\n1,0\n2,1\n";
+        let (space, body) = Space::configure(input, RunType::Real)?;
+        assert_eq!(
+            space,
+            Space {
+                width: 3,
+                height: 2,
+                drop: 1
+            }
+        );
+        assert_eq!(body, "1,0\n2,1\n");
+        assert_eq!(part_one(input, RunType::Real)?, Some(3));
+        assert_eq!(part_two(input, RunType::Real)?, Some("2,1".to_string()));
+
+        let (space, _) = Space::configure("size: 4\n0,0\n", RunType::Example)?;
+        assert_eq!((space.width, space.height, space.drop), (4, 4, 12));
+
+        assert!(Space::configure("depth: 4\n0,0\n", RunType::Example).is_err());
+        assert!(Space::configure("size: 0\n", RunType::Example).is_err());
+
+        // Command line options go on top of the headers
+        let (mut space, _) = Space::configure("size: 4\nbytes: 3\n0,0\n", RunType::Real)?;
+        space.override_with(&Args::new(["--width", "2", "--size", "5", "--show"]))?;
+        assert_eq!((space.width, space.height, space.drop), (2, 5, 3));
+        assert!(space.override_with(&Args::new(["--height", "0"])).is_err());
+        assert!(space.override_with(&Args::new(["--bytes"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_out_of_range() {
+        let err = parse("1,1\n7,2\n", &space(7, 7)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "byte 1 at 7,2 is outside the 7x7 memory space"
+        );
+        assert!(parse("-1,2\n", &space(7, 7)).is_err());
+    }
 }
@@ -1,34 +1,149 @@
-advent_of_code::solution!(19);
+advent_of_code::solution!(@impl 19, [cli_part_one, 1] [cli_part_two, 2]);
 
 use advent_of_code::template::RunType;
 use anyhow::{Context, Result};
+use aoc_solutions::cli::Args;
 use std::collections::BTreeMap;
 
-fn make_towel<'a>(
-    target: &'a str,
-    options: &[&'a str],
-    cache: &mut BTreeMap<&'a str, usize>,
-) -> usize {
-    if target.is_empty() {
-        return 1;
+#[derive(Debug, Default)]
+struct Node {
+    children: BTreeMap<u8, usize>,
+    towel: bool,
+}
+
+/// Every towel stored in a trie, so finding all the towels that fit at a position in a
+/// design is a single walk down from the root however many towels there are.
+#[derive(Debug)]
+struct Towels {
+    nodes: Vec<Node>,
+}
+
+/// Why a design can't be made. `prefix` is the longest start of it that towels can build
+/// and `rest` is what's left that no towel arrangement can continue into.
+#[derive(Debug, PartialEq)]
+struct Impossible<'a> {
+    prefix: &'a str,
+    rest: &'a str,
+}
+
+impl Towels {
+    fn new(towels: &[&str]) -> Self {
+        let mut nodes = vec![Node::default()];
+        for towel in towels {
+            let mut current = 0;
+            for stripe in towel.bytes() {
+                current = match nodes[current].children.get(&stripe) {
+                    Some(next) => *next,
+                    None => {
+                        nodes.push(Node::default());
+                        let next = nodes.len() - 1;
+                        nodes[current].children.insert(stripe, next);
+                        next
+                    }
+                };
+            }
+            nodes[current].towel = true;
+        }
+        Self { nodes }
+    }
+
+    // Where each towel that fits at `start` in the design would end, shortest first
+    fn matches(&self, design: &[u8], start: usize) -> Vec<usize> {
+        let mut out = Vec::new();
+        let mut current = 0;
+        for (idx, stripe) in design.iter().enumerate().skip(start) {
+            let Some(next) = self.nodes[current].children.get(stripe) else {
+                break;
+            };
+            current = *next;
+            if self.nodes[current].towel {
+                out.push(idx + 1);
+            }
+        }
+        out
+    }
+
+    /// `ways[i]` is how many ways there are to finish the design from position `i`, so
+    /// `ways[0]` is the answer for the whole design.
+    fn ways(&self, design: &str) -> Vec<usize> {
+        let design = design.as_bytes();
+        let mut ways = vec![0; design.len() + 1];
+        ways[design.len()] = 1;
+        for start in (0..design.len()).rev() {
+            ways[start] = self
+                .matches(design, start)
+                .into_iter()
+                .map(|end| ways[end])
+                .sum();
+        }
+        ways
     }
 
-    if let Some(val) = cache.get(target) {
-        return *val;
+    fn count(&self, design: &str) -> usize {
+        self.ways(design)[0]
     }
-    let mut out = 0;
-    for option in options {
-        if let Some(after) = target.strip_prefix(option) {
-            out += make_towel(after, options, cache);
+
+    /// Up to `limit` of the ways to make the design, each as the towels used in order.
+    fn decompositions<'a>(&self, design: &'a str, limit: usize) -> Vec<Vec<&'a str>> {
+        fn walk<'a>(
+            towels: &Towels,
+            design: &'a str,
+            ways: &[usize],
+            start: usize,
+            current: &mut Vec<&'a str>,
+            limit: usize,
+            out: &mut Vec<Vec<&'a str>>,
+        ) {
+            if out.len() >= limit {
+                return;
+            }
+            if start == design.len() {
+                out.push(current.clone());
+                return;
+            }
+            for end in towels.matches(design.as_bytes(), start) {
+                // Don't bother with towels that leave something we can't finish
+                if ways[end] == 0 {
+                    continue;
+                }
+                current.push(&design[start..end]);
+                walk(towels, design, ways, end, current, limit, out);
+                current.pop();
+            }
         }
+
+        let ways = self.ways(design);
+        let mut out = Vec::new();
+        if ways[0] > 0 {
+            walk(self, design, &ways, 0, &mut Vec::new(), limit, &mut out);
+        }
+        out
     }
 
-    cache.insert(target, out);
+    /// None if the design can be made, otherwise how far we can get.
+    fn explain<'a>(&self, design: &'a str) -> Option<Impossible<'a>> {
+        let bytes = design.as_bytes();
+        let mut reachable = vec![false; bytes.len() + 1];
+        reachable[0] = true;
+        for start in 0..bytes.len() {
+            if !reachable[start] {
+                continue;
+            }
+            for end in self.matches(bytes, start) {
+                reachable[end] = true;
+            }
+        }
+        if reachable[bytes.len()] {
+            return None;
+        }
 
-    out
+        let longest = reachable.iter().rposition(|r| *r).unwrap_or(0);
+        let (prefix, rest) = design.split_at(longest);
+        Some(Impossible { prefix, rest })
+    }
 }
 
-fn run<F: Fn(usize) -> usize>(input: &str, agg_fn: F) -> Result<Option<usize>> {
+fn parse(input: &str) -> Result<(Towels, Vec<&str>)> {
     let mut lines = input.lines();
 
     let towels: Vec<&str> = lines
@@ -44,11 +159,15 @@ fn run<F: Fn(usize) -> usize>(input: &str, agg_fn: F) -> Result<Option<usize>> {
         }
         patterns.push(line);
     }
+    Ok((Towels::new(&towels), patterns))
+}
+
+fn run<F: Fn(usize) -> usize>(input: &str, agg_fn: F) -> Result<Option<usize>> {
+    let (towels, patterns) = parse(input)?;
 
-    let mut cache = BTreeMap::new();
     let mut possible = 0;
     for pattern in patterns {
-        possible += agg_fn(make_towel(pattern, &towels, &mut cache));
+        possible += agg_fn(towels.count(pattern));
     }
     Ok(Some(possible))
 }
@@ -61,6 +180,34 @@ pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<usize>, anyhow
     run(input, |possible| possible)
 }
 
+/// Part one from the command line. `--explain` shows where every impossible design gets
+/// stuck.
+fn cli_part_one(input: &str, run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
+    if Args::from_env().flag("explain") {
+        let (towels, patterns) = parse(input)?;
+        for pattern in patterns {
+            if let Some(Impossible { prefix, rest }) = towels.explain(pattern) {
+                println!("{}: can build {:?} but not {:?}", pattern, prefix, rest);
+            }
+        }
+    }
+    part_one(input, run_type)
+}
+
+/// Part two from the command line. `--decompositions <limit>` prints up to that many ways
+/// to make each design.
+fn cli_part_two(input: &str, run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
+    if let Some(limit) = Args::from_env().parse("decompositions")? {
+        let (towels, patterns) = parse(input)?;
+        for pattern in patterns {
+            for decomposition in towels.decompositions(pattern, limit) {
+                println!("{}: {}", pattern, decomposition.join(" "));
+            }
+        }
+    }
+    part_two(input, run_type)
+}
+
 #[cfg(test)]
 mod tests_day_19 {
     use super::*;
@@ -84,4 +231,47 @@ mod tests_day_19 {
         assert_eq!(result, expected);
         Ok(())
     }
+
+    #[test]
+    fn test_decompositions() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let (towels, _) = parse(input)?;
+
+        assert_eq!(
+            towels.decompositions("gbbr", 10),
+            vec![
+                vec!["g", "b", "b", "r"],
+                vec!["g", "b", "br"],
+                vec!["gb", "b", "r"],
+                vec!["gb", "br"],
+            ]
+        );
+        assert_eq!(towels.decompositions("gbbr", 2).len(), 2);
+        assert!(towels.decompositions("ubwu", 10).is_empty());
+        assert_eq!(towels.count("rrbgbr"), 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_explain() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let (towels, _) = parse(input)?;
+
+        assert_eq!(towels.explain("brwrr"), None);
+        assert_eq!(
+            towels.explain("ubwu"),
+            Some(Impossible {
+                prefix: "",
+                rest: "ubwu"
+            })
+        );
+        assert_eq!(
+            towels.explain("bbrgwb"),
+            Some(Impossible {
+                prefix: "bbrg",
+                rest: "wb"
+            })
+        );
+        Ok(())
+    }
 }
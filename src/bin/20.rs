@@ -1,9 +1,11 @@
-advent_of_code::solution!(20);
+advent_of_code::solution!(@impl 20, [cli_part_one, 1] [cli_part_two, 2]);
 
 use advent_of_code::template::RunType;
-use aoc_lib::grid::{Direction, Location, Map};
+use aoc_lib::grid::{Direction, Location, Map, UnboundLocation};
+use aoc_solutions::cli::Args;
 
 use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, VecDeque};
 
 #[derive(Debug, Clone, PartialEq)]
 enum Tile {
@@ -40,78 +42,219 @@ impl TryFrom<char> for Tile {
     }
 }
 
-fn build_path(map: &Map<Tile>) -> Result<Vec<Location>> {
-    let start = map
-        .find(|(_, t)| *t == &Tile::Start)
-        .context("failed to find start")?;
+/// BFS distance from `from` to every tile reachable from it, None for walls and anything
+/// cut off.
+fn distances(map: &Map<Tile>, from: &Location) -> Map<Option<usize>> {
+    let mut dist = map.transform(|_, _| None);
+    *dist.get_mut(from) = Some(0);
 
-    let mut path = Vec::new();
-    let mut prev = None;
-    let mut current = start.clone();
-    loop {
-        path.push(current.clone());
-        if let Tile::End = map.get(&current) {
-            break;
-        }
+    let mut queue = VecDeque::from([from.clone()]);
+    while let Some(current) = queue.pop_front() {
+        let d = dist.get(&current).unwrap_or(0);
         for direction in Direction::all() {
             if let Some(next) = map.go_direction(&current, direction) {
-                if Some(&next) == prev.as_ref() {
+                if !map.get(&next).can_enter() || dist.get(&next).is_some() {
                     continue;
                 }
-
-                if !map.get(&next).can_enter() {
-                    continue;
-                }
-
-                prev = Some(current);
-                current = next;
-                break;
+                *dist.get_mut(&next) = Some(d + 1);
+                queue.push_back(next);
             }
         }
     }
-    Ok(path)
+    dist
+}
+
+/// Distances from the start and to the end of the track for every tile, plus the length of
+/// the fastest honest route.
+struct Race {
+    from_start: Map<Option<usize>>,
+    to_end: Map<Option<usize>>,
+    best: usize,
+}
+
+impl Race {
+    fn new(map: &Map<Tile>) -> Result<Self> {
+        let start = map
+            .find(|(_, t)| *t == &Tile::Start)
+            .context("failed to find start")?;
+        let end = map
+            .find(|(_, t)| *t == &Tile::End)
+            .context("failed to find end")?;
+
+        let from_start = distances(map, &start);
+        let best = from_start
+            .get(&end)
+            .context("end can't be reached from the start")?;
+        let to_end = distances(map, &end);
+        Ok(Self {
+            from_start,
+            to_end,
+            best,
+        })
+    }
+
+    fn tiles(distances: &Map<Option<usize>>) -> Vec<(Location, usize)> {
+        distances
+            .iter()
+            .flat_map(|row| row.filter_map(|(location, d)| d.map(|d| (location, d))))
+            .collect()
+    }
 }
 
-fn find_cheats(path: &[Location], save_target: usize, cheat_dist: usize) -> usize {
-    let mut cheats = 0;
-    for (start_idx, start) in path.iter().enumerate() {
-        for (end_idx, end) in path.iter().enumerate().skip(start_idx + save_target) {
+/// How many cheats save each number of picoseconds. A cheat jumps from any tile reachable
+/// from the start to any tile the end can be reached from, as long as they are within
+/// `cheat_dist` of each other. Cheats that don't save anything are left out.
+fn cheat_savings(race: &Race, cheat_dist: usize) -> BTreeMap<usize, usize> {
+    let ends = Race::tiles(&race.to_end);
+
+    let mut savings = BTreeMap::new();
+    for (start, from_start) in Race::tiles(&race.from_start) {
+        for (end, to_end) in &ends {
             let noclip = start.manhattan_dist(end);
-            let normal = end_idx - start_idx;
-            if noclip >= normal {
+            if noclip > cheat_dist {
                 continue;
             }
-            let saved = normal - noclip;
-            if noclip <= cheat_dist && saved >= save_target {
-                cheats += 1;
+            let time = from_start + noclip + to_end;
+            if time < race.best {
+                *savings.entry(race.best - time).or_default() += 1;
             }
         }
     }
-    cheats
+    savings
 }
 
-pub fn part_one(input: &str, run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
-    let map: Map<Tile> = Map::try_from(input).context("Failed to parse input")?;
-    let path = build_path(&map).context("failed to buidl path")?;
-    let save_target = match run_type {
-        RunType::Real => 100,
-        RunType::Example => 12,
+/// Same result as `cheat_savings` but rather than pairing every start with every end it
+/// only looks up the tiles in the diamond within `cheat_dist` of each start, so the work
+/// grows with the area of the diamond instead of the length of the track. Starts are split
+/// across threads.
+fn cheat_savings_scan(race: &Race, cheat_dist: usize) -> BTreeMap<usize, usize> {
+    let starts = Race::tiles(&race.from_start);
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = starts.len().div_ceil(threads).max(1);
+    let reach = cheat_dist as i64;
+
+    let scan = |chunk: &[(Location, usize)]| {
+        let mut savings: BTreeMap<usize, usize> = BTreeMap::new();
+        for (start, from_start) in chunk {
+            for d_row in -reach..=reach {
+                let width = reach - d_row.abs();
+                for d_col in -width..=width {
+                    let Some(end) = UnboundLocation(start.0 as i64 + d_row, start.1 as i64 + d_col)
+                        .to_bounded(&race.to_end)
+                    else {
+                        continue;
+                    };
+                    let Some(to_end) = race.to_end.get(&end) else {
+                        continue;
+                    };
+                    let time = from_start + (d_row.abs() + d_col.abs()) as usize + to_end;
+                    if time < race.best {
+                        *savings.entry(race.best - time).or_default() += 1;
+                    }
+                }
+            }
+        }
+        savings
     };
 
-    let cheats = find_cheats(&path, save_target, 2);
-    Ok(Some(cheats))
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = starts
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || scan(chunk)))
+            .collect();
+
+        let mut savings = BTreeMap::new();
+        for handle in handles {
+            for (saved, count) in handle.join().expect("cheat scan thread panicked") {
+                *savings.entry(saved).or_default() += count;
+            }
+        }
+        savings
+    })
+}
+
+/// Which way to find cheats, both give the same savings
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CheatSearch {
+    /// `cheat_savings`, pairing every start with every end
+    Pairwise,
+    /// `cheat_savings_scan`, only looking within reach of each start
+    Scan,
 }
 
-pub fn part_two(input: &str, run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
-    let map: Map<Tile> = Map::try_from(input).context("Failed to parse input")?;
-    let path = build_path(&map).context("failed to buidl path")?;
-    let save_target = match run_type {
+fn find_cheats(race: &Race, cheat_dist: usize, search: CheatSearch) -> BTreeMap<usize, usize> {
+    match search {
+        CheatSearch::Pairwise => cheat_savings(race, cheat_dist),
+        CheatSearch::Scan => cheat_savings_scan(race, cheat_dist),
+    }
+}
+
+fn cheats_saving(savings: &BTreeMap<usize, usize>, save_target: usize) -> usize {
+    savings.range(save_target..).map(|(_, count)| count).sum()
+}
+
+// Cheats have to save at least 100 picoseconds, the examples ask about smaller savings
+fn save_target(run_type: RunType, example_target: usize) -> usize {
+    match run_type {
         RunType::Real => 100,
-        RunType::Example => 50,
+        RunType::Example => example_target,
+    }
+}
+
+fn race_cheats(
+    input: &str,
+    cheat_dist: usize,
+    search: CheatSearch,
+) -> Result<BTreeMap<usize, usize>> {
+    let map: Map<Tile> = Map::try_from(input).context("Failed to parse input")?;
+    let race = Race::new(&map).context("failed to time race")?;
+    Ok(find_cheats(&race, cheat_dist, search))
+}
+
+pub fn part_one(input: &str, run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
+    let savings = race_cheats(input, 2, CheatSearch::Scan)?;
+    Ok(Some(cheats_saving(&savings, save_target(run_type, 12))))
+}
+
+pub fn part_two(input: &str, run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
+    let savings = race_cheats(input, 20, CheatSearch::Scan)?;
+    Ok(Some(cheats_saving(&savings, save_target(run_type, 50))))
+}
+
+/// Either part from the command line. `--pairwise` times the original every pair search
+/// instead of the scan and `--histogram` prints how many cheats save each amount of time,
+/// like the tables in the puzzle.
+fn cli_cheats(input: &str, save_target: usize, cheat_dist: usize) -> Result<Option<usize>> {
+    let args = Args::from_env();
+    let search = if args.flag("pairwise") {
+        CheatSearch::Pairwise
+    } else {
+        CheatSearch::Scan
     };
+    let savings = race_cheats(input, cheat_dist, search)?;
 
-    let cheats = find_cheats(&path, save_target, 20);
-    Ok(Some(cheats))
+    if args.flag("histogram") {
+        println!("With cheats of up to {} picoseconds:", cheat_dist);
+        for (saved, count) in &savings {
+            if *count == 1 {
+                println!("There is one cheat that saves {} picoseconds.", saved);
+            } else {
+                println!(
+                    "There are {} cheats that save {} picoseconds.",
+                    count, saved
+                );
+            }
+        }
+    }
+    Ok(Some(cheats_saving(&savings, save_target)))
+}
+
+fn cli_part_one(input: &str, run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
+    cli_cheats(input, save_target(run_type, 12), 2)
+}
+
+fn cli_part_two(input: &str, run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
+    cli_cheats(input, save_target(run_type, 50), 20)
 }
 
 #[cfg(test)]
@@ -137,4 +280,105 @@ mod tests_day_20 {
         assert_eq!(result, expected);
         Ok(())
     }
+
+    // The track branches and has dead ends, and the quickest way to E is through the wall
+    // at the bottom
+    const BRANCHING: &str = "\
+###########
+#S........#
+#.###.#.#.#
+#.#...#.#.#
+#.#.#####.#
+#...#E....#
+###########";
+
+    #[test]
+    fn test_branching_track() -> anyhow::Result<()> {
+        let map: Map<Tile> = Map::try_from(BRANCHING)?;
+        let race = Race::new(&map)?;
+        assert_eq!(race.best, 16);
+        let short = find_cheats(&race, 2, CheatSearch::Scan);
+        assert_eq!(short, find_cheats(&race, 2, CheatSearch::Pairwise));
+        assert_eq!(cheats_saving(&short, 4), 3);
+        assert_eq!(cheats_saving(&short, 8), 2);
+        let long = find_cheats(&race, 20, CheatSearch::Scan);
+        assert_eq!(cheats_saving(&long, 4), 56);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unreachable_end() -> anyhow::Result<()> {
+        let walled_in: Map<Tile> = Map::try_from("#####\n#S#E#\n#####")?;
+        assert!(Race::new(&walled_in).is_err());
+
+        let no_end: Map<Tile> = Map::try_from("####\n#S.#\n####")?;
+        assert!(Race::new(&no_end).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cheat_savings() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let map: Map<Tile> = Map::try_from(input.as_str())?;
+        let race = Race::new(&map)?;
+
+        let short = BTreeMap::from([
+            (2, 14),
+            (4, 14),
+            (6, 2),
+            (8, 4),
+            (10, 2),
+            (12, 3),
+            (20, 1),
+            (36, 1),
+            (38, 1),
+            (40, 1),
+            (64, 1),
+        ]);
+        assert_eq!(cheat_savings(&race, 2), short);
+
+        let long = BTreeMap::from([
+            (50, 32),
+            (52, 31),
+            (54, 29),
+            (56, 39),
+            (58, 25),
+            (60, 23),
+            (62, 20),
+            (64, 19),
+            (66, 12),
+            (68, 14),
+            (70, 12),
+            (72, 22),
+            (74, 4),
+            (76, 3),
+        ]);
+        let savings = cheat_savings(&race, 20);
+        assert_eq!(
+            savings
+                .range(50..)
+                .map(|(k, v)| (*k, *v))
+                .collect::<BTreeMap<_, _>>(),
+            long
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cheat_savings_scan() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        for track in [input.as_str(), BRANCHING] {
+            let map: Map<Tile> = Map::try_from(track)?;
+            let race = Race::new(&map)?;
+            for cheat_dist in [0, 1, 2, 3, 10, 20, 50] {
+                assert_eq!(
+                    cheat_savings_scan(&race, cheat_dist),
+                    cheat_savings(&race, cheat_dist),
+                    "cheat_dist={}",
+                    cheat_dist
+                );
+            }
+        }
+        Ok(())
+    }
 }
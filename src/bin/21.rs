@@ -1,305 +1,418 @@
-advent_of_code::solution!(21);
+advent_of_code::solution!(@impl 21, [cli_part_one, 1] [cli_part_two, 2]);
 
 use advent_of_code::template::RunType;
+use aoc_solutions::cli::Args;
 
 use aoc_lib::grid::Location;
-use aoc_lib::parse::preamble::*;
 
 use anyhow::{anyhow, Context, Result};
 use itertools::Itertools;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 
-trait ToLocation {
-    fn start() -> Self;
-    fn void() -> Location;
-    fn to_location(&self) -> Location;
-}
+// The door keypad and the directional keypad every robot is driven from. Keys are separated
+// by spaces, rows by " / " (or newlines) and a blank is a gap the arm can't pass over.
+const DOOR_LAYOUT: &str = "7 8 9 / 4 5 6 / 1 2 3 /   0 A";
+const ROBOT_LAYOUT: &str = "  ^ A / < v >";
 
-#[derive(Debug, Clone, Ord, Eq, PartialEq, PartialOrd, Hash)]
-enum Nums {
-    B0,
-    B1,
-    B2,
-    B3,
-    B4,
-    B5,
-    B6,
-    B7,
-    B8,
-    B9,
-    Activate,
-}
+// Every arm starts over the activate key
+const ACTIVATE: char = 'A';
 
-impl TryFrom<char> for Nums {
-    type Error = anyhow::Error;
-
-    fn try_from(other: char) -> Result<Self> {
-        Ok(match other {
-            '0' => Self::B0,
-            '1' => Self::B1,
-            '2' => Self::B2,
-            '3' => Self::B3,
-            '4' => Self::B4,
-            '5' => Self::B5,
-            '6' => Self::B6,
-            '7' => Self::B7,
-            '8' => Self::B8,
-            '9' => Self::B9,
-            'A' => Self::Activate,
-            other => {
-                return Err(anyhow!("found unexpected nums value {:?}", other));
-            }
-        })
-    }
+#[derive(Debug, Clone)]
+struct Keypad {
+    keys: BTreeMap<char, Location>,
+    at: BTreeMap<Location, char>,
 }
 
-impl ToLocation for Nums {
-    fn start() -> Self {
-        Self::Activate
+impl Keypad {
+    fn parse(layout: &str) -> Result<Self> {
+        let mut keys = BTreeMap::new();
+        let mut at = BTreeMap::new();
+        for (row, line) in layout.split(" / ").flat_map(|r| r.lines()).enumerate() {
+            for (idx, c) in line.chars().enumerate() {
+                // Keys sit on even columns with a space between each of them
+                if idx % 2 == 1 {
+                    if c != ' ' {
+                        return Err(anyhow!(
+                            "expected a space between keys in row {:?}, got {:?}",
+                            line,
+                            c
+                        ));
+                    }
+                    continue;
+                }
+                if c == ' ' {
+                    continue;
+                }
+                let location = Location(row, idx / 2);
+                if keys.insert(c, location.clone()).is_some() {
+                    return Err(anyhow!("key {:?} appears twice in layout", c));
+                }
+                at.insert(location, c);
+            }
+        }
+        if !keys.contains_key(&ACTIVATE) {
+            return Err(anyhow!("layout {:?} has no {} key", layout, ACTIVATE));
+        }
+        Ok(Self { keys, at })
     }
 
-    fn void() -> Location {
-        Location(3, 0)
+    fn location(&self, key: char) -> Result<&Location> {
+        self.keys
+            .get(&key)
+            .context(format!("keypad has no {:?} key", key))
     }
 
-    fn to_location(&self) -> Location {
-        match self {
-            Self::B7 => Location(0, 0),
-            Self::B8 => Location(0, 1),
-            Self::B9 => Location(0, 2),
-            Self::B4 => Location(1, 0),
-            Self::B5 => Location(1, 1),
-            Self::B6 => Location(1, 2),
-            Self::B1 => Location(2, 0),
-            Self::B2 => Location(2, 1),
-            Self::B3 => Location(2, 2),
-            Self::B0 => Location(3, 1),
-            Self::Activate => Location(3, 2),
+    // The keys next to `location` along with the direction pressed to get there
+    fn neighbours(&self, location: &Location) -> Vec<(char, Location)> {
+        let Location(row, col) = *location;
+        let mut out = Vec::new();
+        if row > 0 {
+            out.push(('^', Location(row - 1, col)));
         }
+        out.push(('v', Location(row + 1, col)));
+        if col > 0 {
+            out.push(('<', Location(row, col - 1)));
+        }
+        out.push(('>', Location(row, col + 1)));
+        out.retain(|(_, next)| self.at.contains_key(next));
+        out
     }
 }
 
-#[derive(Debug, Clone, Ord, Eq, PartialEq, PartialOrd, Hash)]
-enum Dir {
-    Up,
-    Down,
-    Left,
-    Right,
-    Activate,
+fn turns(route: &[char]) -> usize {
+    route.windows(2).filter(|w| w[0] != w[1]).count()
 }
 
-impl TryFrom<char> for Dir {
-    type Error = anyhow::Error;
-
-    fn try_from(other: char) -> Result<Self> {
-        Ok(match other {
-            '^' => Self::Up,
-            'v' => Self::Down,
-            '<' => Self::Left,
-            '>' => Self::Right,
-            'A' => Self::Activate,
-            other => {
-                return Err(anyhow!("found unexpected dir value {:?}", other));
+/// Every shortest way to move the arm from `source` to `dest` without passing over a gap.
+fn shortest_routes(keypad: &Keypad, source: char, dest: char) -> Result<Vec<Vec<char>>> {
+    let start = keypad.location(source)?;
+    let end = keypad.location(dest)?;
+
+    // Distance from each key to `dest`, so we can walk downhill from `source`
+    let mut dist = BTreeMap::from([(end.clone(), 0)]);
+    let mut queue = VecDeque::from([end.clone()]);
+    while let Some(location) = queue.pop_front() {
+        let d = dist[&location];
+        for (_, next) in keypad.neighbours(&location) {
+            if !dist.contains_key(&next) {
+                dist.insert(next.clone(), d + 1);
+                queue.push_back(next);
             }
-        })
-    }
-}
-
-impl From<&Dir> for char {
-    fn from(other: &Dir) -> Self {
-        match other {
-            Dir::Up => '^',
-            Dir::Down => 'v',
-            Dir::Left => '<',
-            Dir::Right => '>',
-            Dir::Activate => 'A',
         }
     }
-}
-
-impl ToLocation for Dir {
-    fn start() -> Self {
-        Self::Activate
-    }
-
-    fn void() -> Location {
-        Location(0, 0)
+    if !dist.contains_key(start) {
+        return Err(anyhow!("can't reach {:?} from {:?}", dest, source));
     }
 
-    fn to_location(&self) -> Location {
-        match self {
-            Self::Up => Location(0, 1),
-            Self::Activate => Location(0, 2),
-            Self::Left => Location(1, 0),
-            Self::Down => Location(1, 1),
-            Self::Right => Location(1, 2),
+    let mut routes = vec![(start.clone(), Vec::new())];
+    let mut done = Vec::new();
+    while let Some((location, route)) = routes.pop() {
+        let d = dist[&location];
+        if d == 0 {
+            done.push(route);
+            continue;
         }
-    }
-}
-
-fn go_delta_col(delta_col: i32, out: &mut Vec<Dir>) {
-    for _ in 0..delta_col.abs() {
-        if delta_col < 0 {
-            out.push(Dir::Left);
-        } else {
-            out.push(Dir::Right);
+        for (step, next) in keypad.neighbours(&location) {
+            if dist.get(&next) == Some(&(d - 1)) {
+                let mut route = route.clone();
+                route.push(step);
+                routes.push((next, route));
+            }
         }
     }
-}
 
-fn go_delta_row(delta_row: i32, out: &mut Vec<Dir>) {
-    for _ in 0..delta_row.abs() {
-        if delta_row < 0 {
-            out.push(Dir::Up);
-        } else {
-            out.push(Dir::Down);
-        }
-    }
+    done.sort();
+    Ok(done)
 }
 
-fn get_routes<T: ToLocation>(source: &Location, dest: &Location) -> Vec<Vec<Dir>> {
-    let void = T::void();
-    let delta_row: i32 = dest.0 as i32 - source.0 as i32;
-    let delta_col: i32 = dest.1 as i32 - source.1 as i32;
-
-    // If void is on our row and we want to go to void's column we must fix the row first
-    if source.0 == void.0 && dest.1 == void.1 {
-        let mut out = Vec::new();
-        go_delta_row(delta_row, &mut out);
-        go_delta_col(delta_col, &mut out);
-        return vec![out];
-    }
-
-    // If void is on our column and we want to go to void's row we must fix the column first
-    if source.1 == void.1 && dest.0 == void.0 {
-        let mut out = Vec::new();
-        go_delta_col(delta_col, &mut out);
-        go_delta_row(delta_row, &mut out);
-        return vec![out];
-    }
-    let mut out_1 = Vec::new();
-    go_delta_row(delta_row, &mut out_1);
-    go_delta_col(delta_col, &mut out_1);
-
-    let mut out_2 = Vec::new();
-    go_delta_col(delta_col, &mut out_2);
-    go_delta_row(delta_row, &mut out_2);
-    if out_1 == out_2 {
-        vec![out_1]
-    } else {
-        vec![out_1, out_2]
-    }
+/// The shortest routes from `source` to `dest` that have the fewest turns. Every change of
+/// direction costs the robot driving this keypad extra moves, so we assume a route that
+/// zigzags is never cheaper than one that doesn't. That was worked out for the stock
+/// keypads and isn't proven for any layout, `test_route_pruning` checks it against trying
+/// every route for a few layouts with the gaps moved around.
+fn get_routes(keypad: &Keypad, source: char, dest: char) -> Result<Vec<Vec<char>>> {
+    let mut routes = shortest_routes(keypad, source, dest)?;
+    let fewest = routes.iter().map(|r| turns(r)).min().unwrap_or(0);
+    routes.retain(|r| turns(r) == fewest);
+    Ok(routes)
 }
 
-fn translate<'a, T, I>(mut current: T, codes: I) -> Vec<Vec<Dir>>
-where
-    T: ToLocation + Clone + std::fmt::Debug + 'a,
-    I: Iterator<Item = &'a T>,
-{
+/// Every way of typing `code` on `keypad`, as the presses needed on the keypad driving it.
+fn translate(keypad: &Keypad, code: &[char]) -> Result<Vec<Vec<char>>> {
+    let mut current = ACTIVATE;
     let mut out = Vec::new();
-    for next in codes {
+    for next in code {
         let mut step = Vec::new();
-        for mut route in get_routes::<T>(&current.to_location(), &next.to_location()) {
-            route.push(Dir::Activate);
+        for mut route in get_routes(keypad, current, *next)? {
+            route.push(ACTIVATE);
             step.push(route);
         }
-        current = next.clone();
+        current = *next;
         out.push(step);
     }
-    out.into_iter()
+    Ok(out
+        .into_iter()
         .multi_cartesian_product()
         .map(|x| x.into_iter().flatten().collect())
-        .collect()
+        .collect())
 }
 
-fn nums_to_int(nums: &[Nums]) -> u64 {
+fn nums_to_int(code: &[char]) -> u64 {
     let mut out = 0;
-    for num in nums {
-        let n = match num {
-            Nums::B0 => 0,
-            Nums::B1 => 1,
-            Nums::B2 => 2,
-            Nums::B3 => 3,
-            Nums::B4 => 4,
-            Nums::B5 => 5,
-            Nums::B6 => 6,
-            Nums::B7 => 7,
-            Nums::B8 => 8,
-            Nums::B9 => 9,
-            Nums::Activate => break,
+    for num in code {
+        let Some(n) = num.to_digit(10) else {
+            break;
         };
         out *= 10;
-        out += n;
+        out += n as u64;
     }
     out
 }
 
+// Each robot layer multiplies the cost by roughly 2.5, so a u128 lasts for about 90 layers
+// and past that we report overflow rather than wrapping
+type Cost = u128;
+type CostCache = BTreeMap<(char, char, usize), Cost>;
+
+fn overflow() -> anyhow::Error {
+    anyhow!("cost doesn't fit in a u128, too many robots")
+}
+
+/// Cost to the human of moving the arm over `pads[level]` from `source` to `dest` and
+/// pressing it. The human presses the last keypad directly and every other one is driven
+/// by a robot arm over the keypad after it.
 fn cost_to_go(
-    source: Dir,
-    dest: Dir,
+    pads: &[Keypad],
+    source: char,
+    dest: char,
     level: usize,
-    robot_layers: usize,
-    cache: &mut BTreeMap<(Location, Location, usize), usize>,
-) -> usize {
+    cache: &mut CostCache,
+) -> Result<Cost> {
     // It costs us 1 to press te button
-    if level >= robot_layers {
-        return 1;
+    if level + 1 >= pads.len() {
+        return Ok(1);
     }
 
-    let key = (source.to_location(), dest.to_location(), level);
+    let key = (source, dest, level);
     if let Some(cached) = cache.get(&key) {
-        return *cached;
+        return Ok(*cached);
     }
 
     let mut shortest = None;
-    for mut route in get_routes::<Dir>(&source.to_location(), &dest.to_location()) {
-        route.push(Dir::Activate);
-        let mut cost = 0;
-        let mut current = Dir::start();
-        for step in route {
-            cost += cost_to_go(current, step.clone(), level + 1, robot_layers, cache);
-            current = step;
-        }
+    for mut route in get_routes(&pads[level], source, dest)? {
+        route.push(ACTIVATE);
+        let cost = route_cost(pads, &route, level + 1, cache)?;
         shortest = Some(std::cmp::min(shortest.unwrap_or(cost), cost));
     }
 
-    let cost = shortest.expect("Should always find at least 1 route");
+    let cost = shortest.context("Should always find at least 1 route")?;
     cache.insert(key, cost);
-    cost
+    Ok(cost)
+}
+
+// Cost of pressing every key in `route` on `pads[level]`, starting from activate
+fn route_cost(
+    pads: &[Keypad],
+    route: &[char],
+    level: usize,
+    cache: &mut CostCache,
+) -> Result<Cost> {
+    let mut cost: Cost = 0;
+    let mut current = ACTIVATE;
+    for step in route {
+        cost = cost
+            .checked_add(cost_to_go(pads, current, *step, level, cache)?)
+            .ok_or_else(overflow)?;
+        current = *step;
+    }
+    Ok(cost)
+}
+
+// Longest key sequence we are willing to build out in full
+const MAX_SEQUENCE: Cost = 1 << 20;
+
+// Appends the presses the human makes for `cost_to_go(pads, source, dest, level)`, taking
+// the cheapest route at every level
+fn expand(
+    pads: &[Keypad],
+    source: char,
+    dest: char,
+    level: usize,
+    cache: &mut CostCache,
+    out: &mut Vec<char>,
+) -> Result<()> {
+    if level + 1 >= pads.len() {
+        out.push(dest);
+        return Ok(());
+    }
+
+    let mut best: Option<(Cost, Vec<char>)> = None;
+    for mut route in get_routes(&pads[level], source, dest)? {
+        route.push(ACTIVATE);
+        let cost = route_cost(pads, &route, level + 1, cache)?;
+        if best.as_ref().is_none_or(|(best_cost, _)| cost < *best_cost) {
+            best = Some((cost, route));
+        }
+    }
+
+    let (_, route) = best.context("Should always find at least 1 route")?;
+    let mut current = ACTIVATE;
+    for step in route {
+        expand(pads, current, step, level + 1, cache, out)?;
+        current = step;
+    }
+    Ok(())
+}
+
+/// One of the shortest sequences of presses the human can make to type `code`. Fails if
+/// that sequence would be too long to build, which happens quickly as robots are added.
+fn reconstruct(door: &Keypad, pads: &[Keypad], code: &[char]) -> Result<Vec<char>> {
+    let mut cache = BTreeMap::new();
+
+    let mut best: Option<(Cost, Vec<char>)> = None;
+    for route in translate(door, code)? {
+        let cost = route_cost(pads, &route, 0, &mut cache)?;
+        if best.as_ref().is_none_or(|(best_cost, _)| cost < *best_cost) {
+            best = Some((cost, route));
+        }
+    }
+    let (cost, route) = best.context("code has no routes")?;
+    if cost > MAX_SEQUENCE {
+        return Err(anyhow!(
+            "shortest sequence is {} presses, too long to build (max {})",
+            cost,
+            MAX_SEQUENCE
+        ));
+    }
+
+    let mut out = Vec::with_capacity(cost as usize);
+    let mut current = ACTIVATE;
+    for step in route {
+        expand(pads, current, step, 0, &mut cache, &mut out)?;
+        current = step;
+    }
+    Ok(out)
+}
+
+// Presses `key` on the keypad driving the arm over `keypad`, either moving the arm or
+// returning the key under it
+fn press(keypad: &Keypad, arm: &mut Location, key: char) -> Result<Option<char>> {
+    if key == ACTIVATE {
+        let under = keypad.at.get(arm).context("arm isn't over a key")?;
+        return Ok(Some(*under));
+    }
+    let (_, next) = keypad
+        .neighbours(arm)
+        .into_iter()
+        .find(|(step, _)| *step == key)
+        .context(format!(
+            "pressing {:?} at {:?} moves the arm into the void",
+            key, arm
+        ))?;
+    *arm = next;
+    Ok(None)
+}
+
+/// Replays the human's presses down through every robot and returns what gets typed on the
+/// door, failing if any arm is ever moved over a gap.
+fn replay(door: &Keypad, pads: &[Keypad], presses: &[char]) -> Result<Vec<char>> {
+    let robots = pads.len().saturating_sub(1);
+    let mut arms: Vec<Location> = pads[..robots]
+        .iter()
+        .map(|pad| pad.location(ACTIVATE).cloned())
+        .collect::<Result<_>>()?;
+    let mut door_arm = door.location(ACTIVATE)?.clone();
+
+    let mut typed = Vec::new();
+    for (idx, human) in presses.iter().enumerate() {
+        let mut key = Some(*human);
+        for level in (0..robots).rev() {
+            let Some(pressed) = key else {
+                break;
+            };
+            key = press(&pads[level], &mut arms[level], pressed)
+                .context(format!("press {} at robot {}", idx, level))?;
+        }
+        if let Some(pressed) = key {
+            if let Some(out) =
+                press(door, &mut door_arm, pressed).context(format!("press {} at the door", idx))?
+            {
+                typed.push(out);
+            }
+        }
+    }
+    Ok(typed)
+}
+
+/// The door keypad and a stack of `robot_layers` robots on directional keypads, plus the
+/// one the human uses.
+fn keypad_stack(robot_layers: usize) -> Result<(Keypad, Vec<Keypad>)> {
+    let door = Keypad::parse(DOOR_LAYOUT)?;
+    let robot = Keypad::parse(ROBOT_LAYOUT)?;
+    Ok((door, vec![robot; robot_layers + 1]))
 }
 
-fn run(input: &str, robot_layers: usize) -> Result<Option<u64>> {
-    let data: Vec<Vec<Nums>> =
-        parse_input(LineSplitter, Chars(TryFromChar), input).context("failed to parse input")?;
+fn run(input: &str, door: &Keypad, pads: &[Keypad]) -> Result<Option<Cost>> {
+    let data: Vec<Vec<char>> = input.lines().map(|l| l.chars().collect()).collect();
 
-    let mut out: u64 = 0;
+    let mut out: Cost = 0;
     let mut cache = BTreeMap::new();
     for code in &data {
-        let mut shortest: Option<usize> = None;
-        let code_routes = translate(Nums::start(), code.iter());
+        let mut shortest: Option<Cost> = None;
+        let code_routes = translate(door, code)?;
         for route in code_routes {
-            let mut route_cost = 0;
-            let mut current = Dir::start();
-            for step in route {
-                route_cost += cost_to_go(current, step.clone(), 0, robot_layers, &mut cache);
-                current = step;
-            }
-            shortest = Some(std::cmp::min(shortest.unwrap_or(route_cost), route_cost));
+            let cost = route_cost(pads, &route, 0, &mut cache)?;
+            shortest = Some(std::cmp::min(shortest.unwrap_or(cost), cost));
         }
 
-        let int_code = nums_to_int(code);
-        let code_cost = shortest.unwrap();
-        out += int_code * code_cost as u64;
+        let int_code = nums_to_int(code) as Cost;
+        let code_cost = shortest.context("code has no routes")?;
+        out = int_code
+            .checked_mul(code_cost)
+            .and_then(|complexity| out.checked_add(complexity))
+            .ok_or_else(overflow)?;
     }
     Ok(Some(out))
 }
 
-pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
-    run(input, 2)
+pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<Cost>, anyhow::Error> {
+    let (door, pads) = keypad_stack(2)?;
+    run(input, &door, &pads)
+}
+
+/// Part one from the command line. `--show` prints what the human actually presses for
+/// each code, checked by replaying it through the robots.
+fn cli_part_one(input: &str, run_type: RunType) -> Result<Option<Cost>, anyhow::Error> {
+    if Args::from_env().flag("show") {
+        let (door, pads) = keypad_stack(2)?;
+        for line in input.lines() {
+            let code: Vec<char> = line.chars().collect();
+            let presses = reconstruct(&door, &pads, &code)?;
+            if replay(&door, &pads, &presses)? != code {
+                return Err(anyhow!(
+                    "replaying presses for {} typed something else",
+                    line
+                ));
+            }
+            println!("{}: {}", line, presses.iter().collect::<String>());
+        }
+    }
+    part_one(input, run_type)
 }
 
-pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
-    run(input, 25)
+pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<Cost>, anyhow::Error> {
+    let (door, pads) = keypad_stack(25)?;
+    run(input, &door, &pads)
+}
+
+/// Part two from the command line. `--robots <count>` changes how many robots sit
+/// between the human and the door.
+fn cli_part_two(input: &str, run_type: RunType) -> Result<Option<Cost>, anyhow::Error> {
+    let Some(robots) = Args::from_env().parse("robots")? else {
+        return part_two(input, run_type);
+    };
+    let (door, pads) = keypad_stack(robots)?;
+    run(input, &door, &pads)
 }
 
 #[cfg(test)]
@@ -318,11 +431,147 @@ mod tests_day_21 {
 
     #[test]
     fn test_part_two() -> anyhow::Result<()> {
-        let expected = None;
+        let expected = Some(154115708116294);
         let input = &advent_of_code::template::read_file_part("examples", DAY, 2);
         assert!(expected.is_none() || !input.is_empty(), "example 2 empty!");
         let result = part_two(input, RunType::Example)?;
         assert_eq!(result, expected);
         Ok(())
     }
+
+    #[test]
+    fn test_get_routes() -> anyhow::Result<()> {
+        let (door, pads) = keypad_stack(2)?;
+        let routes = |keypad, source, dest| -> anyhow::Result<Vec<String>> {
+            Ok(get_routes(keypad, source, dest)?
+                .into_iter()
+                .map(|r| r.into_iter().collect())
+                .collect())
+        };
+        assert_eq!(routes(&door, 'A', '1')?, vec!["^<<"]);
+        assert_eq!(routes(&door, '0', '9')?, vec![">^^^", "^^^>"]);
+        assert_eq!(routes(&pads[0], '<', '^')?, vec![">^"]);
+
+        // A gap in the middle means going around it either way
+        let holey = Keypad::parse("7 8 9 / 4   6 / 1 2 3 /   0 A")?;
+        assert_eq!(routes(&holey, '4', '6')?, vec!["^>>v", "v>>^"]);
+        assert_eq!(routes(&holey, '5', '6').ok(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keypad_layouts() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+
+        // Mirroring every keypad top to bottom (so up and down swap too) moves the gaps
+        // but can't change the cost
+        let door = Keypad::parse("  0 A / 1 2 3 / 4 5 6 / 7 8 9")?;
+        let pads = vec![Keypad::parse("< ^ > /   v A")?; 3];
+        assert_eq!(run(input, &door, &pads)?, Some(126384));
+
+        assert!(Keypad::parse("1 2 3").is_err());
+        assert!(Keypad::parse("1 A 1").is_err());
+        assert!(Keypad::parse("12 A").is_err());
+        Ok(())
+    }
+
+    // cost_to_go without the cache or the fewest turns pruning
+    fn unpruned_cost(pads: &[Keypad], source: char, dest: char, level: usize) -> Result<Cost> {
+        if level + 1 >= pads.len() {
+            return Ok(1);
+        }
+        let mut shortest = None;
+        for mut route in shortest_routes(&pads[level], source, dest)? {
+            route.push(ACTIVATE);
+            let mut cost = 0;
+            let mut current = ACTIVATE;
+            for step in route {
+                cost += unpruned_cost(pads, current, step, level + 1)?;
+                current = step;
+            }
+            shortest = Some(std::cmp::min(shortest.unwrap_or(cost), cost));
+        }
+        shortest.context("expected a route")
+    }
+
+    #[test]
+    fn test_route_pruning() -> anyhow::Result<()> {
+        let doors = [
+            DOOR_LAYOUT,
+            "7 8 9 / 4 5 6 / 1 2 3 / 0 A",
+            "7 8 9 / 4   6 / 1 2 3 / 5 0 A",
+            "  0 A / 1 2 3 / 4 5 6 / 7 8 9",
+        ];
+        let robots = [ROBOT_LAYOUT, "^ A / < v >", "< v > /   ^ A", "< ^ > / v A"];
+        for door_layout in doors {
+            for robot_layout in robots {
+                let door = Keypad::parse(door_layout)?;
+                let robot = Keypad::parse(robot_layout)?;
+                let mut pads = vec![door.clone()];
+                pads.extend(vec![robot; 3]);
+
+                let mut cache = BTreeMap::new();
+                for source in door.keys.keys() {
+                    for dest in door.keys.keys() {
+                        assert_eq!(
+                            cost_to_go(&pads, *source, *dest, 0, &mut cache)?,
+                            unpruned_cost(&pads, *source, *dest, 0)?,
+                            "{:?} to {:?} on {:?} driven by {:?}",
+                            source,
+                            dest,
+                            door_layout,
+                            robot_layout
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconstruct() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let expected_lengths = [68, 60, 68, 64, 64];
+        for robots in 0..=3 {
+            let (door, pads) = keypad_stack(robots)?;
+            for (line, expected) in input.lines().zip(expected_lengths) {
+                let code: Vec<char> = line.chars().collect();
+                let presses = reconstruct(&door, &pads, &code)?;
+                if robots == 2 {
+                    assert_eq!(presses.len(), expected);
+                }
+                assert_eq!(replay(&door, &pads, &presses)?, code);
+            }
+        }
+
+        let (door, pads) = keypad_stack(25)?;
+        assert!(reconstruct(&door, &pads, &['0', 'A']).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay() -> anyhow::Result<()> {
+        let (door, pads) = keypad_stack(0)?;
+        let presses: Vec<char> = "<A^A>^^AvvvA".chars().collect();
+        assert_eq!(replay(&door, &pads, &presses)?, vec!['0', '2', '9', 'A']);
+
+        // The second left runs the robot's arm off the edge of its keypad
+        let (door, pads) = keypad_stack(1)?;
+        assert!(replay(&door, &pads, &['<', '<']).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_deep_robots() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+
+        let (door, pads) = keypad_stack(80)?;
+        let deep = run(input, &door, &pads)?.context("expected a result")?;
+        assert!(deep > u64::MAX as Cost);
+
+        let (door, pads) = keypad_stack(200)?;
+        assert!(run(input, &door, &pads).is_err());
+        Ok(())
+    }
 }
@@ -1,17 +1,195 @@
-advent_of_code::solution!(22);
+advent_of_code::solution!(@impl 22, [cli_part_one, 1] [cli_part_two, 2]);
 
 use advent_of_code::template::RunType;
+use aoc_solutions::cli::Args;
 
-use anyhow::{Context, Result};
-use std::collections::VecDeque;
+use anyhow::{anyhow, Context, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shift {
+    Left(u32),
+    Right(u32),
+}
+
+impl std::str::FromStr for Shift {
+    type Err = anyhow::Error;
+
+    // `L6` for a left shift by 6 (multiply by 64), `R5` for a right shift by 5
+    fn from_str(s: &str) -> Result<Self> {
+        let amount = |rest: &str| {
+            rest.parse()
+                .context(format!("failed to parse shift amount {:?}", rest))
+        };
+        if let Some(rest) = s.strip_prefix('L') {
+            Ok(Shift::Left(amount(rest)?))
+        } else if let Some(rest) = s.strip_prefix('R') {
+            Ok(Shift::Right(amount(rest)?))
+        } else {
+            Err(anyhow!("expected shift like L6 or R5, got {:?}", s))
+        }
+    }
+}
+
+/// How secrets get mixed: each shift in turn is XORed back into the secret and the result
+/// pruned to the modulus. The modulus has to be a power of two so that pruning is a mask,
+/// which keeps the whole step linear over GF(2).
+#[derive(Debug, Clone, PartialEq)]
+struct SecretParams {
+    shifts: Vec<Shift>,
+    bits: u32,
+}
+
+impl Default for SecretParams {
+    fn default() -> Self {
+        // Multiply by 64, divide by 32, multiply by 2048, all modulo 16777216
+        Self {
+            shifts: vec![Shift::Left(6), Shift::Right(5), Shift::Left(11)],
+            bits: 24,
+        }
+    }
+}
+
+impl SecretParams {
+    fn new(shifts: Vec<Shift>, modulus: u64) -> Result<Self> {
+        if modulus < 2 || !modulus.is_power_of_two() {
+            return Err(anyhow!("modulus {} must be a power of two", modulus));
+        }
+        let bits = modulus.trailing_zeros();
+        for shift in &shifts {
+            let (Shift::Left(amount) | Shift::Right(amount)) = shift;
+            // A zero shift clears the secret and anything wider is a no-op, either way
+            // we couldn't run it backwards
+            if *amount == 0 || *amount >= bits {
+                return Err(anyhow!("{:?} must be between 1 and {}", shift, bits - 1));
+            }
+        }
+        Ok(Self { shifts, bits })
+    }
+
+    fn mask(&self) -> u64 {
+        (1 << self.bits) - 1
+    }
+
+    fn mix(&self, secret: u64) -> u64 {
+        let mut secret = secret & self.mask();
+        for shift in &self.shifts {
+            secret = match shift {
+                Shift::Left(amount) => (secret ^ (secret << amount)) & self.mask(),
+                Shift::Right(amount) => secret ^ (secret >> amount),
+            };
+        }
+        secret
+    }
+
+    // `y = x ^ (x << n)` is undone by `x = y ^ (y << n) ^ (y << 2n) ^ ...` until the shift
+    // pushes everything past the modulus, and the same for right shifts
+    fn unmix(&self, secret: u64) -> u64 {
+        let mut secret = secret & self.mask();
+        for shift in self.shifts.iter().rev() {
+            let mut undone = secret;
+            let mut by = match shift {
+                Shift::Left(amount) | Shift::Right(amount) => *amount,
+            };
+            while by < self.bits {
+                undone ^= match shift {
+                    Shift::Left(_) => (secret << by) & self.mask(),
+                    Shift::Right(_) => secret >> by,
+                };
+                by += match shift {
+                    Shift::Left(amount) | Shift::Right(amount) => *amount,
+                };
+            }
+            secret = undone;
+        }
+        secret
+    }
+
+    /// A matrix that moves a secret `n` steps along the sequence, backwards when `n` is
+    /// negative. Takes O(bits^2 log n) so any `n` is cheap.
+    fn jump_matrix(&self, n: i64) -> BitMatrix {
+        let step = if n < 0 {
+            BitMatrix::from_fn(self.bits, |v| self.unmix(v))
+        } else {
+            BitMatrix::from_fn(self.bits, |v| self.mix(v))
+        };
+        step.pow(n.unsigned_abs())
+    }
+}
+
+/// A square matrix over GF(2), stored as the image of each basis bit.
+#[derive(Debug, Clone, PartialEq)]
+struct BitMatrix {
+    columns: Vec<u64>,
+}
+
+impl BitMatrix {
+    fn identity(bits: u32) -> Self {
+        Self {
+            columns: (0..bits).map(|bit| 1 << bit).collect(),
+        }
+    }
+
+    // Only valid for a function that is linear over GF(2)
+    fn from_fn(bits: u32, f: impl Fn(u64) -> u64) -> Self {
+        Self {
+            columns: (0..bits).map(|bit| f(1 << bit)).collect(),
+        }
+    }
+
+    fn apply(&self, value: u64) -> u64 {
+        self.columns
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| (value >> bit) & 1 == 1)
+            .fold(0, |acc, (_, column)| acc ^ column)
+    }
+
+    // self after other
+    fn compose(&self, other: &Self) -> Self {
+        Self {
+            columns: other.columns.iter().map(|c| self.apply(*c)).collect(),
+        }
+    }
+
+    fn pow(&self, mut n: u64) -> Self {
+        let mut out = Self::identity(self.columns.len() as u32);
+        let mut square = self.clone();
+        while n > 0 {
+            if n & 1 == 1 {
+                out = square.compose(&out);
+            }
+            square = square.compose(&square);
+            n >>= 1;
+        }
+        out
+    }
+}
 
 struct SecretGen {
     secret: u64,
+    params: SecretParams,
 }
 
 impl SecretGen {
     fn new(secret: u64) -> Self {
-        Self { secret }
+        Self::with_params(secret, SecretParams::default())
+    }
+
+    fn with_params(secret: u64, params: SecretParams) -> Self {
+        Self { secret, params }
+    }
+
+    /// Steps back to the secret before the current one and returns it.
+    fn prev(&mut self) -> u64 {
+        self.secret = self.params.unmix(self.secret);
+        self.secret
+    }
+
+    /// Moves `n` secrets along without generating the ones in between, backwards when `n`
+    /// is negative, and returns the secret landed on.
+    fn jump(&mut self, n: i64) -> u64 {
+        self.secret = self.params.jump_matrix(n).apply(self.secret);
+        self.secret
     }
 }
 
@@ -19,109 +197,227 @@ impl Iterator for SecretGen {
     type Item = u64;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let step1 = (self.secret ^ (self.secret * 64)) % 16777216;
-        let step2 = (step1 ^ (step1 / 32)) % 16777216;
-        let step3 = (step2 ^ (step2 * 2048)) % 16777216;
-        self.secret = step3;
-        Some(step3)
+        self.secret = self.params.mix(self.secret);
+        Some(self.secret)
     }
 }
 
-pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
-    let numbers: Vec<u64> = input
+fn parse(input: &str) -> Result<Vec<u64>> {
+    input
         .lines()
         .map(|l| l.parse())
         .collect::<Result<Vec<u64>, std::num::ParseIntError>>()
-        .context("failed to parse input")?;
+        .context("failed to parse input")
+}
+
+pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
+    let numbers = parse(input)?;
+
+    // Every buyer makes the same jump so work it out once
+    let jump = SecretParams::default().jump_matrix(STEPS as i64);
 
     let mut out = 0;
     for number in numbers {
-        let initial = number;
-        let secret = SecretGen::new(initial).nth(1999).unwrap();
-        out += secret;
+        out += jump.apply(number);
     }
     Ok(Some(out))
 }
 
-const MAX_NUMBERS: usize = 2000;
+// e.g. `cargo run --bin 22 -- --secret 123 --steps 1000000000000` to jump straight to a far
+// off secret (negative steps go backwards) or `--secret 123 --back 5` to list the secrets
+// leading up to one. `--shifts L6,R5,L11 --modulus 16777216` swaps in another generator.
+fn explore_secrets(args: &Args) -> Result<()> {
+    let Some(secret) = args.parse::<u64>("secret")? else {
+        return Ok(());
+    };
+    let shifts = match args.value("shifts")? {
+        Some(shifts) => shifts
+            .split(',')
+            .map(|shift| shift.parse())
+            .collect::<Result<Vec<Shift>>>()?,
+        None => SecretParams::default().shifts,
+    };
+    let modulus = args.parse("modulus")?.unwrap_or(16777216);
 
-#[derive(Debug)]
-struct ComboCache {
-    total: u64,
-    prices: [Option<u64>; MAX_NUMBERS],
+    let mut secrets = SecretGen::with_params(secret, SecretParams::new(shifts, modulus)?);
+    if let Some(steps) = args.parse::<i64>("steps")? {
+        println!("{} steps from {}: {}", steps, secret, secrets.jump(steps));
+    }
+    if let Some(back) = args.parse::<usize>("back")? {
+        let mut secrets = SecretGen::with_params(secret, secrets.params.clone());
+        for step in 1..=back {
+            println!("{} steps before {}: {}", step, secret, secrets.prev());
+        }
+    }
+    Ok(())
+}
+
+fn cli_part_one(input: &str, run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
+    explore_secrets(&Args::from_env())?;
+    part_one(input, run_type)
 }
 
-impl ComboCache {
+// How many new secrets each buyer generates in a day
+const STEPS: usize = 2000;
+
+// Prices are 0-9 so each change is in -9..=9, a single base 19 digit once offset by 9
+const DELTA_RANGE: usize = 19;
+const WINDOWS: usize = DELTA_RANGE.pow(4);
+
+/// Every window of four price changes a buyer goes through, as `(secret_idx, window, price)`
+/// where `secret_idx` counts the secrets generated so far and `price` is what the buyer
+/// sells for at the end of the window.
+fn price_windows(initial: u64) -> impl Iterator<Item = (usize, usize, u64)> {
+    let mut window = 0;
+    let mut prev = initial % 10;
+    SecretGen::new(initial)
+        .take(STEPS)
+        .enumerate()
+        .filter_map(move |(step, secret)| {
+            let price = secret % 10;
+            let delta = (price + 9 - prev) as usize;
+            window = (window * DELTA_RANGE + delta) % WINDOWS;
+            prev = price;
+
+            // The first three steps don't have a full window yet
+            if step < 3 {
+                return None;
+            }
+            Some((step + 1, window, price))
+        })
+}
+
+fn window_changes(window: usize) -> [i64; 4] {
+    let mut changes = [0; 4];
+    let mut rest = window;
+    for change in changes.iter_mut().rev() {
+        *change = (rest % DELTA_RANGE) as i64 - 9;
+        rest /= DELTA_RANGE;
+    }
+    changes
+}
+
+/// For every window of four price changes, packed into a single index, the bananas it would
+/// earn summed over all buyers added so far. `last_seen` stamps each window with the last
+/// buyer to hit it so only a buyer's first occurrence counts, which keeps memory fixed no
+/// matter how many buyers there are.
+struct SequenceIndex {
+    totals: Vec<u64>,
+    last_seen: Vec<usize>,
+    buyers: usize,
+}
+
+impl SequenceIndex {
     fn new() -> Self {
         Self {
-            total: 0,
-            prices: [None; MAX_NUMBERS],
+            totals: vec![0; WINDOWS],
+            last_seen: vec![0; WINDOWS],
+            buyers: 0,
+        }
+    }
+
+    fn add_buyer(&mut self, initial: u64) {
+        // Stamps start at 1 so that 0 means nobody has hit the window yet
+        self.buyers += 1;
+        let stamp = self.buyers;
+
+        for (_, window, price) in price_windows(initial) {
+            if self.last_seen[window] == stamp {
+                continue;
+            }
+            self.last_seen[window] = stamp;
+            self.totals[window] += price;
         }
     }
 
-    fn add(&mut self, number: usize, price: u64) -> u64 {
-        if self.prices[number].is_none() {
-            self.prices[number] = Some(price);
-            self.total += price;
+    // The window earning the most bananas and its total, ties going to the lowest window
+    fn best(&self) -> Option<(usize, u64)> {
+        if self.buyers == 0 {
+            return None;
         }
-        self.total
+        self.totals
+            .iter()
+            .enumerate()
+            .max_by_key(|(window, total)| (**total, std::cmp::Reverse(*window)))
+            .map(|(window, total)| (window, *total))
     }
 }
 
+/// Where a buyer first sees the winning changes, `secret_idx` being how many secrets in
+/// they are and `price` what they sell for.
+#[derive(Debug, PartialEq)]
+struct Sale {
+    secret_idx: usize,
+    price: u64,
+}
+
+#[derive(Debug, PartialEq)]
+struct BestSequence {
+    changes: [i64; 4],
+    total: u64,
+    // One entry per buyer in input order, None for buyers that never see the sequence
+    sales: Vec<Option<Sale>>,
+}
+
+fn best_sequence(numbers: &[u64]) -> Option<BestSequence> {
+    let mut index = SequenceIndex::new();
+    for number in numbers {
+        index.add_buyer(*number);
+    }
+    let (best, total) = index.best()?;
+
+    let sales = numbers
+        .iter()
+        .map(|number| {
+            price_windows(*number)
+                .find(|(_, window, _)| *window == best)
+                .map(|(secret_idx, _, price)| Sale { secret_idx, price })
+        })
+        .collect();
+
+    Some(BestSequence {
+        changes: window_changes(best),
+        total,
+        sales,
+    })
+}
+
 pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
-    let numbers: Vec<u64> = input
-        .lines()
-        .map(|l| l.parse())
-        .collect::<Result<Vec<u64>, std::num::ParseIntError>>()
-        .context("failed to parse input")?;
+    let numbers = parse(input)?;
 
-    let mut cache_table: Vec<Vec<Vec<Vec<ComboCache>>>> = Vec::with_capacity(20);
-    for _ in 0..20 {
-        let mut l1 = Vec::with_capacity(20);
-        for _ in 0..20 {
-            let mut l2 = Vec::with_capacity(20);
-            for _ in 0..20 {
-                let mut l3 = Vec::with_capacity(20);
-                for _ in 0..20 {
-                    l3.push(ComboCache::new());
-                }
-                l2.push(l3);
-            }
-            l1.push(l2);
-        }
-        cache_table.push(l1);
-    }
-    // You can replace this monstrosity with below. It's slowe but doesn't depend on
-    // the exact AoC problem details as much.
-    // let mut cache: BTreeMap<Vec<u64>, ComboCache> = BTreeMap::new();
-    let mut best: Option<u64> = None;
-    for (number_id, number) in numbers.iter().enumerate() {
-        let mut deltas = VecDeque::new();
-        let secrets = SecretGen::new(*number).take(2000);
-        let mut prev: i64 = *number as i64 % 10;
-        for secret in secrets {
-            let price = secret % 10;
-            deltas.push_back(prev - price as i64);
-            prev = price as i64;
-            match deltas.len() {
-                0..=4 => continue,
-                5 => {
-                    deltas.pop_front();
-                    let mut delta_iter = deltas.iter();
-                    let cache_entry = &mut cache_table[(*delta_iter.next().unwrap() + 10) as usize]
-                        [(*delta_iter.next().unwrap() + 10) as usize]
-                        [(*delta_iter.next().unwrap() + 10) as usize]
-                        [(*delta_iter.next().unwrap() + 10) as usize];
-
-                    let combo_price = cache_entry.add(number_id, price);
-                    best = Some(std::cmp::max(best.unwrap_or(combo_price), combo_price));
-                }
-                _ => unreachable!(),
-            }
-        }
+    let Some(best) = best_sequence(&numbers) else {
+        return Ok(None);
+    };
+
+    Ok(Some(best.total))
+}
+
+/// Part two from the command line. `--leaderboard` also lists what every buyer paid for
+/// the best sequence, highest price first.
+fn cli_part_two(input: &str, run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
+    if !Args::from_env().flag("leaderboard") {
+        return part_two(input, run_type);
     }
 
-    Ok(Some(best.unwrap()))
+    let numbers = parse(input)?;
+    let Some(best) = best_sequence(&numbers) else {
+        return Ok(None);
+    };
+    println!("sequence: {:?}", best.changes);
+    let mut board: Vec<(usize, &Sale)> = best
+        .sales
+        .iter()
+        .enumerate()
+        .filter_map(|(buyer, sale)| sale.as_ref().map(|sale| (buyer, sale)))
+        .collect();
+    board.sort_by_key(|(buyer, sale)| (std::cmp::Reverse(sale.price), sale.secret_idx, *buyer));
+    for (buyer, sale) in board {
+        println!(
+            "buyer {} ({}): {} bananas at secret {}",
+            buyer, numbers[buyer], sale.price, sale.secret_idx
+        );
+    }
+    Ok(Some(best.total))
 }
 
 #[cfg(test)]
@@ -148,4 +444,94 @@ mod tests_day_22 {
         assert_eq!(result, expected);
         Ok(())
     }
+
+    #[test]
+    fn test_part_two_many_buyers() -> anyhow::Result<()> {
+        let single = part_two("123", RunType::Example)?.context("no best for one buyer")?;
+
+        // More buyers than the old fixed size cache could hold
+        let input = vec!["123"; 5000].join("\n");
+        let result = part_two(&input, RunType::Example)?;
+        assert_eq!(result, Some(single * 5000));
+        Ok(())
+    }
+
+    #[test]
+    fn test_best_sequence() -> anyhow::Result<()> {
+        let best = best_sequence(&[1, 2, 3, 2024]).context("expected a best sequence")?;
+        assert_eq!(
+            best,
+            BestSequence {
+                changes: [-2, 1, -1, 3],
+                total: 23,
+                sales: vec![
+                    Some(Sale {
+                        secret_idx: 1964,
+                        price: 7
+                    }),
+                    Some(Sale {
+                        secret_idx: 291,
+                        price: 7
+                    }),
+                    None,
+                    Some(Sale {
+                        secret_idx: 455,
+                        price: 9
+                    }),
+                ],
+            }
+        );
+        assert_eq!(best_sequence(&[]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_secret_gen_jump() {
+        let mut secrets = SecretGen::new(123);
+        assert_eq!(secrets.next(), Some(15887950));
+        assert_eq!(secrets.prev(), 123);
+
+        let expected = SecretGen::new(1).nth(1999);
+        assert_eq!(Some(SecretGen::new(1).jump(2000)), expected);
+
+        let mut secrets = SecretGen::new(2024);
+        let far = secrets.jump(1_000_000_000_000);
+        assert_eq!(
+            secrets.jump(-999_999_999_000),
+            SecretGen::new(2024).jump(1000)
+        );
+        assert_eq!(secrets.jump(999_999_999_000), far);
+        assert_eq!(secrets.jump(-1_000_000_000_000), 2024);
+    }
+
+    #[test]
+    fn test_secret_params() -> anyhow::Result<()> {
+        let params = SecretParams::new(vec![Shift::Right(3), Shift::Left(2)], 256)?;
+        let mut secrets = SecretGen::with_params(77, params.clone());
+        let mut expected = 77;
+        for _ in 0..20 {
+            expected = expected ^ (expected >> 3);
+            expected = (expected ^ (expected * 4)) % 256;
+            assert_eq!(secrets.next(), Some(expected));
+        }
+        for _ in 0..20 {
+            secrets.prev();
+        }
+        assert_eq!(secrets.secret, 77);
+        assert_eq!(SecretGen::with_params(77, params).jump(20), expected);
+
+        assert!(SecretParams::new(vec![Shift::Left(6)], 1000).is_err());
+        assert!(SecretParams::new(vec![Shift::Left(0)], 1024).is_err());
+        assert!(SecretParams::new(vec![Shift::Right(10)], 1024).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shift_parse() -> anyhow::Result<()> {
+        assert_eq!("L6".parse::<Shift>()?, Shift::Left(6));
+        assert_eq!("R5".parse::<Shift>()?, Shift::Right(5));
+        assert!("X5".parse::<Shift>().is_err());
+        assert!("L".parse::<Shift>().is_err());
+        Ok(())
+    }
 }
@@ -1,6 +1,7 @@
-advent_of_code::solution!(23);
+advent_of_code::solution!(@impl 23, [cli_part_one, 1] [cli_part_two, 2]);
 
 use advent_of_code::template::RunType;
+use aoc_solutions::cli::Args;
 
 use aoc_lib::parse::preamble::*;
 
@@ -20,64 +21,196 @@ fn build_connections(input: &str) -> Result<BTreeMap<&str, BTreeSet<&str>>> {
     Ok(connections)
 }
 
-pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
-    let connections = build_connections(input)?;
-
-    let mut out: BTreeSet<BTreeSet<String>> = BTreeSet::new();
-    for a in connections.keys() {
-        if !a.starts_with("t") {
+// Grows `clique` one node at a time, only ever adding nodes that sort after the ones already
+// in it so each clique is built exactly once and in order.
+fn extend_cliques<'a>(
+    connections: &BTreeMap<&'a str, BTreeSet<&'a str>>,
+    clique: &mut Vec<&'a str>,
+    candidates: &BTreeSet<&'a str>,
+    k: usize,
+    out: &mut Vec<Vec<&'a str>>,
+) {
+    if clique.len() == k {
+        out.push(clique.clone());
+        return;
+    }
+    for node in candidates {
+        let Some(neighbours) = connections.get(node) else {
             continue;
+        };
+        let next: BTreeSet<&str> = candidates
+            .iter()
+            .filter(|other| *other > node && neighbours.contains(*other))
+            .cloned()
+            .collect();
+        clique.push(node);
+        extend_cliques(connections, clique, &next, k, out);
+        clique.pop();
+    }
+}
+
+/// Every clique of exactly `k` computers that has at least one computer matching `filter`
+/// (when there is one). Each clique is sorted and so is the list of them.
+fn cliques<'a>(
+    connections: &BTreeMap<&'a str, BTreeSet<&'a str>>,
+    k: usize,
+    filter: Option<&dyn Fn(&str) -> bool>,
+) -> Vec<Vec<&'a str>> {
+    let mut out = Vec::new();
+    if k == 0 {
+        return out;
+    }
+    extend_cliques(
+        connections,
+        &mut Vec::new(),
+        &connections.keys().cloned().collect(),
+        k,
+        &mut out,
+    );
+    if let Some(filter) = filter {
+        out.retain(|clique| clique.iter().any(|node| filter(node)));
+    }
+    out
+}
+
+/// Renders the network as an undirected Graphviz DOT graph with the computers in
+/// `highlight` and the links between them drawn in red.
+fn to_dot(connections: &BTreeMap<&str, BTreeSet<&str>>, highlight: &[&str]) -> String {
+    let mut out = Vec::new();
+    out.push("graph lan {".to_string());
+    for node in connections.keys() {
+        if highlight.contains(node) {
+            out.push(format!("  {} [style=filled, fillcolor=red];", node));
+        } else {
+            out.push(format!("  {};", node));
         }
-        if let Some(a_targets) = connections.get(a) {
-            for b in a_targets {
-                if a == b {
-                    continue;
-                }
-                if let Some(b_targets) = connections.get(b) {
-                    for c in b_targets.intersection(a_targets) {
-                        if !a_targets.contains(c) {
-                            continue;
-                        }
-                        if c == b || c == a {
-                            continue;
-                        }
-                        out.insert(
-                            [a.to_string(), b.to_string(), c.to_string()]
-                                .into_iter()
-                                .collect(),
-                        );
-                    }
-                }
+    }
+    for (a, neighbours) in connections {
+        for b in neighbours.iter().filter(|b| *b > a) {
+            if highlight.contains(a) && highlight.contains(b) {
+                out.push(format!("  {} -- {} [color=red, penwidth=3];", a, b));
+            } else {
+                out.push(format!("  {} -- {};", a, b));
             }
         }
     }
+    out.push("}".to_string());
+    out.join("\n")
+}
+
+pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
+    let connections = build_connections(input)?;
 
-    Ok(Some(out.len()))
+    let triangles = cliques(&connections, 3, Some(&|node| node.starts_with('t')));
+    Ok(Some(triangles.len()))
 }
 
-pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<String>, anyhow::Error> {
+/// Part one from the command line. `--triangles` prints every triangle that was counted.
+fn cli_part_one(input: &str, run_type: RunType) -> Result<Option<usize>, anyhow::Error> {
+    if !Args::from_env().flag("triangles") {
+        return part_one(input, run_type);
+    }
+
     let connections = build_connections(input)?;
+    let triangles = cliques(&connections, 3, Some(&|node| node.starts_with('t')));
+    for clique in &triangles {
+        println!("{}", clique.join(","));
+    }
+    Ok(Some(triangles.len()))
+}
 
-    let mut hist: BTreeMap<String, usize> = BTreeMap::new();
-    for (a, a_dests) in &connections {
-        for (b, b_dests) in &connections {
-            if a == b {
-                continue;
-            }
-            let mut inter: Vec<&str> = a_dests.intersection(b_dests).cloned().collect();
-            if !(a_dests.contains(a) && b_dests.contains(a)) {
-                inter.push(a);
-            }
-            if !(a_dests.contains(b) && b_dests.contains(b)) {
-                inter.push(b);
-            }
-            inter.sort();
-            *hist.entry(inter.join(",")).or_default() += 1;
+/// Bron–Kerbosch with pivoting. `clique` is the clique built so far, `candidates` the nodes
+/// that could still extend it and `excluded` the nodes already tried, so each maximal clique
+/// is only reported once. The largest one seen is kept in `best`, ties going to whichever
+/// sorts first.
+fn bron_kerbosch<'a>(
+    connections: &BTreeMap<&'a str, BTreeSet<&'a str>>,
+    clique: &mut Vec<&'a str>,
+    mut candidates: BTreeSet<&'a str>,
+    mut excluded: BTreeSet<&'a str>,
+    best: &mut Vec<&'a str>,
+) {
+    // Even taking every candidate won't beat what we have
+    if clique.len() + candidates.len() < best.len() {
+        return;
+    }
+
+    let neighbours = |node: &str| connections.get(node).cloned().unwrap_or_default();
+    let Some(pivot) = candidates
+        .union(&excluded)
+        .max_by_key(|node| neighbours(node).intersection(&candidates).count())
+        .cloned()
+    else {
+        // Nothing can extend the clique or has already been tried so it is maximal
+        let mut found = clique.clone();
+        found.sort();
+        if found.len() > best.len() || (found.len() == best.len() && found < *best) {
+            *best = found;
         }
+        return;
+    };
+
+    // Any maximal clique contains the pivot or one of its non-neighbours, so we only
+    // need to branch on those
+    let pivot_neighbours = neighbours(pivot);
+    let branches: Vec<&str> = candidates.difference(&pivot_neighbours).cloned().collect();
+    for node in branches {
+        let node_neighbours = neighbours(node);
+        clique.push(node);
+        bron_kerbosch(
+            connections,
+            clique,
+            candidates.intersection(&node_neighbours).cloned().collect(),
+            excluded.intersection(&node_neighbours).cloned().collect(),
+            best,
+        );
+        clique.pop();
+
+        candidates.remove(node);
+        excluded.insert(node);
     }
+}
 
-    let (max_group, _) = hist.into_iter().max_by_key(|(_, v)| *v).unwrap();
-    Ok(Some(max_group))
+fn max_clique<'a>(connections: &BTreeMap<&'a str, BTreeSet<&'a str>>) -> Vec<&'a str> {
+    let mut best = Vec::new();
+    bron_kerbosch(
+        connections,
+        &mut Vec::new(),
+        connections.keys().cloned().collect(),
+        BTreeSet::new(),
+        &mut best,
+    );
+    best
+}
+
+pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<String>, anyhow::Error> {
+    let connections = build_connections(input)?;
+
+    let clique = max_clique(&connections);
+    if clique.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(clique.join(",")))
+}
+
+/// Part two from the command line. `--dot <file>` writes the network with the password
+/// clique highlighted, e.g. `--dot lan.dot` then `neato -Tsvg lan.dot`.
+fn cli_part_two(input: &str, run_type: RunType) -> Result<Option<String>, anyhow::Error> {
+    let args = Args::from_env();
+    let Some(path) = args.value("dot")? else {
+        return part_two(input, run_type);
+    };
+
+    let connections = build_connections(input)?;
+    let clique = max_clique(&connections);
+    if clique.is_empty() {
+        return Ok(None);
+    }
+
+    std::fs::write(path, to_dot(&connections, &clique))
+        .context(format!("failed to write DOT graph {:?}", path))?;
+    Ok(Some(clique.join(",")))
 }
 
 #[cfg(test)]
@@ -103,4 +236,74 @@ mod tests_day_23 {
         assert_eq!(result, expected);
         Ok(())
     }
+
+    #[test]
+    fn test_part_two_not_a_clique() -> anyhow::Result<()> {
+        // Every node is connected except kc-kd, the most common set of shared neighbours
+        // is all five nodes even though they aren't a clique
+        let mut lines = Vec::new();
+        for a in ["ka", "kb", "kc", "kd", "ke"] {
+            for b in ["ka", "kb", "kc", "kd", "ke"] {
+                if a < b && (a, b) != ("kc", "kd") {
+                    lines.push(format!("{}-{}", a, b));
+                }
+            }
+        }
+        let input = lines.join("\n");
+
+        let result = part_two(&input, RunType::Example)?;
+        assert_eq!(result, Some("ka,kb,kc,ke".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cliques() -> anyhow::Result<()> {
+        let input = &advent_of_code::template::read_file_part("examples", DAY, 1);
+        let connections = build_connections(input)?;
+
+        assert_eq!(cliques(&connections, 3, None).len(), 12);
+        assert_eq!(
+            cliques(&connections, 3, Some(&|node| node.starts_with('t'))),
+            vec![
+                vec!["co", "de", "ta"],
+                vec!["co", "ka", "ta"],
+                vec!["de", "ka", "ta"],
+                vec!["qp", "td", "wh"],
+                vec!["tb", "vc", "wq"],
+                vec!["tc", "td", "wh"],
+                vec!["td", "wh", "yn"],
+            ]
+        );
+        assert_eq!(
+            cliques(&connections, 4, None),
+            vec![vec!["co", "de", "ka", "ta"]]
+        );
+        assert!(cliques(&connections, 5, None).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_dot() -> anyhow::Result<()> {
+        let connections = build_connections("aa-bb\nbb-cc\ncc-aa\ncc-dd")?;
+        let clique = max_clique(&connections);
+        assert_eq!(clique, vec!["aa", "bb", "cc"]);
+
+        let dot = to_dot(&connections, &clique);
+        assert_eq!(
+            dot.lines().collect::<Vec<_>>(),
+            vec![
+                "graph lan {",
+                "  aa [style=filled, fillcolor=red];",
+                "  bb [style=filled, fillcolor=red];",
+                "  cc [style=filled, fillcolor=red];",
+                "  dd;",
+                "  aa -- bb [color=red, penwidth=3];",
+                "  aa -- cc [color=red, penwidth=3];",
+                "  bb -- cc [color=red, penwidth=3];",
+                "  cc -- dd;",
+                "}",
+            ]
+        );
+        Ok(())
+    }
 }
@@ -1,36 +1,45 @@
-#![allow(unused_imports)]
-advent_of_code::solution!(24);
+advent_of_code::solution!(@impl 24, [part_one, 1] [cli_part_two, 2]);
 
 use advent_of_code::template::RunType;
-
-use aoc_lib::grid::{CountingMap, Direction, Location, Map};
-use aoc_lib::parse::preamble::*;
+use aoc_solutions::cli::Args;
 
 use anyhow::{anyhow, Context, Result};
-use std::collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque};
-use std::str::FromStr;
+use itertools::Itertools;
+use std::collections::{BTreeMap, BTreeSet};
 
 #[derive(Debug, Clone, Ord, Eq, PartialEq, PartialOrd, Hash)]
 enum Mapping {
     Or { a: String, b: String },
     And { a: String, b: String },
     Xor { a: String, b: String },
+    Nand { a: String, b: String },
+    Nor { a: String, b: String },
+    Xnor { a: String, b: String },
+    Not { a: String },
 }
 
 impl Mapping {
     fn parse(other: &str) -> Result<(String, Self)> {
         let (ops, out) = other.split_once(" -> ").context("failed to split ->")?;
+        let out = out.to_string();
+
+        if let Some(a) = ops.strip_prefix("NOT ") {
+            return Ok((out, Mapping::Not { a: a.to_string() }));
+        }
+
         let (a, rest) = ops.split_once(" ").context("failed to split out a")?;
         let (op, b) = rest.split_once(" ").context("failed to split out op")?;
 
         let a = a.to_string();
         let b = b.to_string();
-        let out = out.to_string();
 
         let m = match op {
             "OR" => Mapping::Or { a, b },
             "AND" => Mapping::And { a, b },
             "XOR" => Mapping::Xor { a, b },
+            "NAND" => Mapping::Nand { a, b },
+            "NOR" => Mapping::Nor { a, b },
+            "XNOR" => Mapping::Xnor { a, b },
             _ => {
                 return Err(anyhow!("got unexpected op: {:?}", op));
             }
@@ -39,22 +48,23 @@ impl Mapping {
         Ok((out, m))
     }
 
-    fn ab(&self) -> (&str, &str) {
+    fn inputs(&self) -> Vec<&str> {
         match self {
-            Self::Or { a, b } => (a, b),
-            Self::And { a, b } => (a, b),
-            Self::Xor { a, b } => (a, b),
+            Self::Or { a, b }
+            | Self::And { a, b }
+            | Self::Xor { a, b }
+            | Self::Nand { a, b }
+            | Self::Nor { a, b }
+            | Self::Xnor { a, b } => vec![a, b],
+            Self::Not { a } => vec![a],
         }
     }
 
     fn matching_ab(&self, target: &str) -> Option<&str> {
-        let (a, b) = self.ab();
-        if a == target {
-            return Some(b);
-        } else if b == target {
-            return Some(a);
-        } else {
-            return None;
+        match self.inputs()[..] {
+            [a, b] if a == target => Some(b),
+            [a, b] if b == target => Some(a),
+            _ => None,
         }
     }
 
@@ -63,167 +73,249 @@ impl Mapping {
             Self::Or { .. } => "OR",
             Self::And { .. } => "AND",
             Self::Xor { .. } => "XOR",
+            Self::Nand { .. } => "NAND",
+            Self::Nor { .. } => "NOR",
+            Self::Xnor { .. } => "XNOR",
+            Self::Not { .. } => "NOT",
         }
     }
-}
 
-fn resolve_wire(
-    wire: &str,
-    wires: &BTreeMap<String, Mapping>,
-    inputs: &BTreeMap<String, bool>,
-    cache: &mut BTreeMap<String, bool>,
-    depth: usize,
-    print: bool,
-) -> Result<bool> {
-    if let Some(input) = inputs.get(wire) {
-        return Ok(*input);
+    // `b` is ignored by the single input NOT
+    fn apply(&self, a: bool, b: bool) -> bool {
+        match self {
+            Self::Or { .. } => a | b,
+            Self::And { .. } => a & b,
+            Self::Xor { .. } => a ^ b,
+            Self::Nand { .. } => !(a & b),
+            Self::Nor { .. } => !(a | b),
+            Self::Xnor { .. } => !(a ^ b),
+            Self::Not { .. } => !a,
+        }
     }
-    if let Some(cached) = cache.get(wire) {
-        return Ok(*cached);
+
+    // What this gate would be in a ripple-carry adder, judging only by its own inputs
+    fn gate_type(&self) -> Option<GateType> {
+        let from_inputs = self.inputs().iter().all(|w| w.starts_with(['x', 'y']));
+        match (self, from_inputs) {
+            (Self::Or { .. }, _) => Some(GateType::OutCarry),
+            (Self::Xor { .. }, true) => Some(GateType::RawSum),
+            (Self::Xor { .. }, false) => Some(GateType::OutSum),
+            (Self::And { .. }, true) => Some(GateType::RawCarry),
+            (Self::And { .. }, false) => Some(GateType::InnerCarry),
+            _ => None,
+        }
     }
+}
 
-    let prefix: String = (0..depth).map(|_| ' ').collect();
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+enum SimError {
+    #[error("found a cycle through {0}")]
+    Cycle(String),
+    #[error("{0} is read but is neither an input nor driven by a gate")]
+    Undriven(String),
+    #[error("{0} is an input but is also driven by a gate")]
+    DrivenInput(String),
+    #[error("{0} is not an input")]
+    NotAnInput(String),
+    #[error("{0} is past the end of a u128 bus")]
+    TooWide(String),
+    #[error("{1} doesn't fit on the {0} bus")]
+    BusOverflow(char, u128),
+}
 
-    let map = wires.get(wire).context("expected to find wire!")?;
-    if print {
-        println!("{}{}={:?}", prefix, wire, map);
-    }
-    let value = match map {
-        Mapping::And { a, b } => {
-            let a_value = resolve_wire(&a, wires, inputs, cache, depth + 1, print)
-                .context("failed to resolve AND a")?;
-            let b_value = resolve_wire(&b, wires, inputs, cache, depth + 1, print)
-                .context("failed to resolve AND b")?;
+// Wires named like `x07` make up bit 7 of bus `x`
+fn bus_bit(wire: &str, bus: char) -> Option<usize> {
+    wire.strip_prefix(bus)?.parse().ok()
+}
 
-            a_value & b_value
-        }
-        Mapping::Or { a, b } => {
-            let a_value = resolve_wire(&a, wires, inputs, cache, depth + 1, print)
-                .context("failed to resolve OR a")?;
-            let b_value = resolve_wire(&b, wires, inputs, cache, depth + 1, print)
-                .context("failed to resolve OR b")?;
+#[derive(Debug)]
+struct Gate {
+    out: usize,
+    mapping: Mapping,
+    a: usize,
+    b: usize,
+}
 
-            a_value | b_value
-        }
-        Mapping::Xor { a, b } => {
-            let a_value = resolve_wire(&a, wires, inputs, cache, depth + 1, print)
-                .context("failed to resolve XOR a")?;
-            let b_value = resolve_wire(&b, wires, inputs, cache, depth + 1, print)
-                .context("failed to resolve XOR b")?;
+/// A netlist compiled down to wire indices with its gates in topological order, so every
+/// simulation is a single pass over the gates with no recursion.
+#[derive(Debug)]
+struct Circuit {
+    names: Vec<String>,
+    inputs: Vec<usize>,
+    gates: Vec<Gate>,
+    values: Vec<bool>,
+    // Every wire named like a bus bit as `(bus, bit, wire, is_input)`, so reading and
+    // writing buses doesn't have to parse names every time
+    bus_wires: Vec<(char, usize, usize, bool)>,
+}
 
-            a_value ^ b_value
+impl Circuit {
+    fn new<'a>(
+        wires: &'a BTreeMap<String, Mapping>,
+        inputs: impl IntoIterator<Item = &'a String>,
+    ) -> Result<Self, SimError> {
+        let mut names = Vec::new();
+        let mut index: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut id = |wire: &'a str| -> usize {
+            *index.entry(wire).or_insert_with(|| {
+                names.push(wire.to_string());
+                names.len() - 1
+            })
+        };
+
+        let mut input_ids = Vec::new();
+        for wire in inputs {
+            if wires.contains_key(wire) {
+                return Err(SimError::DrivenInput(wire.clone()));
+            }
+            input_ids.push(id(wire));
         }
-    };
 
-    cache.insert(wire.to_string(), value);
-    Ok(value)
-}
+        let mut gates = Vec::new();
+        for (out, mapping) in wires {
+            let inputs = mapping.inputs();
+            let a = id(inputs[0]);
+            let b = inputs.get(1).map(|w| id(w)).unwrap_or(a);
+            gates.push(Gate {
+                out: id(out),
+                mapping: mapping.clone(),
+                a,
+                b,
+            });
+        }
 
-pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
-    let (inits, maps_str) = input
-        .split_once("\n\n")
-        .context("Failed to split sections")?;
+        let mut driver = vec![None; names.len()];
+        for (gate_idx, gate) in gates.iter().enumerate() {
+            driver[gate.out] = Some(gate_idx);
+        }
+        let mut is_input = vec![false; names.len()];
+        for idx in &input_ids {
+            is_input[*idx] = true;
+        }
+        for gate in &gates {
+            for wire in [gate.a, gate.b] {
+                if driver[wire].is_none() && !is_input[wire] {
+                    return Err(SimError::Undriven(names[wire].clone()));
+                }
+            }
+        }
 
-    let mut inputs = BTreeMap::new();
-    for line in inits.lines() {
-        let (wire, value) = line
-            .trim()
-            .split_once(": ")
-            .context("failed to split wire init line")?;
+        // Kahn's algorithm, a gate is ready once every gate feeding it has been placed
+        let mut waiting = vec![0; gates.len()];
+        let mut readers = vec![Vec::new(); names.len()];
+        for (gate_idx, gate) in gates.iter().enumerate() {
+            for wire in [gate.a, gate.b] {
+                if driver[wire].is_some() {
+                    waiting[gate_idx] += 1;
+                    readers[wire].push(gate_idx);
+                }
+            }
+        }
+        let mut ready: Vec<usize> = (0..gates.len()).filter(|g| waiting[*g] == 0).collect();
+        let mut order = Vec::with_capacity(gates.len());
+        while let Some(gate_idx) = ready.pop() {
+            order.push(gate_idx);
+            for reader in &readers[gates[gate_idx].out] {
+                waiting[*reader] -= 1;
+                if waiting[*reader] == 0 {
+                    ready.push(*reader);
+                }
+            }
+        }
+        // Anything left over is waiting on itself
+        if let Some(stuck) = (0..gates.len()).find(|g| waiting[*g] != 0) {
+            return Err(SimError::Cycle(names[gates[stuck].out].clone()));
+        }
 
-        let value = if value == "1" {
-            true
-        } else if value == "0" {
-            false
-        } else {
-            return Err(anyhow!("Got unexpected wire value: {:?}", value));
-        };
+        let mut gates: Vec<Option<Gate>> = gates.into_iter().map(Some).collect();
+        let gates = order
+            .into_iter()
+            .filter_map(|gate_idx| gates[gate_idx].take())
+            .collect();
+
+        let mut bus_wires = Vec::new();
+        for (idx, name) in names.iter().enumerate() {
+            let mut chars = name.chars();
+            if let Some(bus) = chars.next() {
+                if let Ok(bit) = chars.as_str().parse() {
+                    bus_wires.push((bus, bit, idx, is_input[idx]));
+                }
+            }
+        }
 
-        inputs.insert(wire.to_string(), value);
+        Ok(Self {
+            values: vec![false; names.len()],
+            names,
+            inputs: input_ids,
+            gates,
+            bus_wires,
+        })
     }
 
-    let mut wires = BTreeMap::new();
-    for map in maps_str.lines() {
-        let (out, m) = Mapping::parse(map).context("failed to parse mapping")?;
-        wires.insert(out, m);
+    fn set(&mut self, wire: &str, value: bool) -> Result<(), SimError> {
+        let idx = self
+            .inputs
+            .iter()
+            .find(|idx| self.names[**idx] == wire)
+            .ok_or_else(|| SimError::NotAnInput(wire.to_string()))?;
+        self.values[*idx] = value;
+        Ok(())
     }
 
-    let mut cache = BTreeMap::new();
-    let mut out = 0;
-    for wire in wires.keys() {
-        if let Some(wire_num) = wire.strip_prefix('z') {
-            if resolve_wire(wire, &wires, &inputs, &mut cache, 0, false)
-                .context("failed to resolve wire")?
-            {
-                let v: u64 = wire_num.parse().context("failed to parse after z")?;
-                out += 1 << v;
+    /// Sets every input wire on `bus` from the bits of `value`.
+    fn set_bus(&mut self, bus: char, value: u128) -> Result<(), SimError> {
+        let mut remaining = value;
+        for (wire_bus, bit, idx, is_input) in &self.bus_wires {
+            if *wire_bus != bus || !is_input {
+                continue;
+            }
+            if *bit >= 128 {
+                return Err(SimError::TooWide(self.names[*idx].clone()));
             }
+            self.values[*idx] = (value >> bit) & 1 == 1;
+            remaining &= !(1 << bit);
+        }
+        if remaining != 0 {
+            return Err(SimError::BusOverflow(bus, value));
         }
+        Ok(())
     }
-    Ok(Some(out))
-}
 
-fn extract_xy(a: &str, b: &str) -> Option<(u64, u64)> {
-    if let (Some(x), Some(y)) = (a.strip_prefix('x'), b.strip_prefix('y')) {
-        Some((x.parse().unwrap(), y.parse().unwrap()))
-    } else if let (Some(y), Some(x)) = (a.strip_prefix('y'), b.strip_prefix('x')) {
-        Some((x.parse().unwrap(), y.parse().unwrap()))
-    } else {
-        None
+    fn run(&mut self) {
+        for gate in &self.gates {
+            self.values[gate.out] = gate.mapping.apply(self.values[gate.a], self.values[gate.b]);
+        }
     }
-}
-
-fn is_adder(idx: usize, wire: &str, wires: &BTreeMap<String, Mapping>) {
-    let map = wires.get(wire).unwrap();
-    println!("{} {:?}", idx, map);
-    if let Mapping::Xor { a, b } = map {
-        let a_map = wires.get(a).unwrap();
-        let b_map = wires.get(b).unwrap();
 
-        let sum = if let Mapping::Xor { .. } = a_map {
-            a_map
-        } else if let Mapping::Xor { .. } = b_map {
-            b_map
-        } else {
-            println!("E: {} Can't find SUM XOR!", wire);
-            return;
-        };
-        let cout = if let Mapping::Or { .. } = a_map {
-            a_map
-        } else if let Mapping::Or { .. } = b_map {
-            b_map
-        } else {
-            println!("E: {} Can't find cout OR!", wire);
-            return;
-        };
-
-        println!("{} has sum={:?} cout={:?}", wire, sum, cout);
-        println!("{}={:?} {}={:?}", a, wires.get(a), b, wires.get(b));
-
-        if let Mapping::Xor { a: sum_a, b: sum_b } = sum {
-            if let Some((x, y)) = extract_xy(sum_a, sum_b) {
-                assert!(x == y);
-                if x != idx as u64 {
-                    println!("E: {} Sum for {} uses {} as input", wire, idx, x);
-                }
-            } else {
-                println!("Sum is not input wires!");
+    /// Reads every wire on `bus` as one number, as of the last run.
+    fn bus(&self, bus: char) -> Result<u128, SimError> {
+        let mut out = 0;
+        for (wire_bus, bit, idx, _) in &self.bus_wires {
+            if *wire_bus != bus {
+                continue;
+            }
+            if *bit >= 128 {
+                return Err(SimError::TooWide(self.names[*idx].clone()));
+            }
+            if self.values[*idx] {
+                out |= 1 << bit;
             }
-        } else {
-            unreachable!();
         }
-    } else {
-        println!("E: {} Top level must be an XOR!", wire);
+        Ok(out)
     }
 }
 
-#[derive(Debug)]
-enum GateTypeIdx {
-    RawCarry(u64),
-    RawSum(u64),
-    InnerCarry(u64),
-    OutCarry(u64),
-    OutSum(u64),
+pub fn part_one(input: &str, _run_type: RunType) -> Result<Option<u64>, anyhow::Error> {
+    let (inputs, wires) = parse(input)?;
+
+    let mut circuit = Circuit::new(&wires, inputs.keys()).context("failed to build circuit")?;
+    for (wire, value) in &inputs {
+        circuit.set(wire, *value)?;
+    }
+    circuit.run();
+
+    let out = circuit.bus('z')?;
+    Ok(Some(u64::try_from(out).context("z doesn't fit in a u64")?))
 }
 
 #[derive(Debug, Clone, Ord, Eq, PartialEq, PartialOrd, Hash)]
@@ -235,30 +327,19 @@ enum GateType {
     OutSum,
 }
 
-impl From<&Mapping> for (GateType, Option<u64>) {
-    fn from(mapping: &Mapping) -> (GateType, Option<u64>) {
-        match mapping {
-            Mapping::Or { .. } => (GateType::OutCarry, None),
-            Mapping::Xor { a, b } => {
-                if let Some((x, y)) = extract_xy(a, b) {
-                    assert!(x == y);
-                    (GateType::RawSum, Some(x))
-                } else {
-                    (GateType::OutSum, None)
-                }
-            }
-            Mapping::And { a, b } => {
-                if let Some((x, y)) = extract_xy(a, b) {
-                    assert!(x == y);
-                    (GateType::RawCarry, Some(x))
-                } else {
-                    (GateType::InnerCarry, None)
-                }
-            }
+impl GateType {
+    fn colour(&self) -> &'static str {
+        match self {
+            Self::RawCarry => "lightblue",
+            Self::RawSum => "palegreen",
+            Self::InnerCarry => "lightskyblue",
+            Self::OutCarry => "khaki",
+            Self::OutSum => "mediumseagreen",
         }
     }
 }
 
+/// One full adder in the ripple-carry chain, named by the wire each gate drives.
 #[derive(Debug)]
 struct AddUnit {
     idx: u64,
@@ -271,73 +352,608 @@ struct AddUnit {
 }
 
 impl AddUnit {
-    fn mappings(&self) -> Vec<(Mapping, String)> {
+    fn gates(&self) -> Vec<(GateType, Mapping, String)> {
         let x = format!("x{:02}", self.idx);
         let y = format!("y{:02}", self.idx);
-        let z = format!("z{:02}", self.idx);
-        let mut links = Vec::new();
-        links.push((
-            Mapping::And {
-                a: x.clone(),
-                b: y.clone(),
-            },
-            self.raw_carry.clone(),
-        ));
-        links.push((
-            Mapping::Xor {
-                a: x.clone(),
-                b: y.clone(),
-            },
-            self.raw_sum.clone(),
-        ));
-        links.push((
-            Mapping::Xor {
-                a: self.input_carry.clone(),
-                b: self.raw_sum.clone(),
-            },
-            z,
-        ));
-        links.push((
-            Mapping::Xor {
-                a: self.input_carry.clone(),
-                b: self.raw_sum.clone(),
-            },
-            self.out_sum.clone(),
-        ));
-        links.push((
-            Mapping::And {
-                a: self.input_carry.clone(),
-                b: self.raw_sum.clone(),
-            },
-            self.inner_carry.clone(),
-        ));
-        links.push((
-            Mapping::Or {
-                a: self.inner_carry.clone(),
-                b: self.raw_carry.clone(),
-            },
-            self.out_carry.clone(),
+        vec![
+            (
+                GateType::RawCarry,
+                Mapping::And {
+                    a: x.clone(),
+                    b: y.clone(),
+                },
+                self.raw_carry.clone(),
+            ),
+            (
+                GateType::RawSum,
+                Mapping::Xor { a: x, b: y },
+                self.raw_sum.clone(),
+            ),
+            (
+                GateType::OutSum,
+                Mapping::Xor {
+                    a: self.input_carry.clone(),
+                    b: self.raw_sum.clone(),
+                },
+                self.out_sum.clone(),
+            ),
+            (
+                GateType::InnerCarry,
+                Mapping::And {
+                    a: self.input_carry.clone(),
+                    b: self.raw_sum.clone(),
+                },
+                self.inner_carry.clone(),
+            ),
+            (
+                GateType::OutCarry,
+                Mapping::Or {
+                    a: self.inner_carry.clone(),
+                    b: self.raw_carry.clone(),
+                },
+                self.out_carry.clone(),
+            ),
+        ]
+    }
+}
+
+/// Gates indexed both by the wire they drive and by their inputs, so that swapping the
+/// outputs of two gates keeps both views in sync.
+struct Netlist {
+    wires: BTreeMap<String, Mapping>,
+    by_inputs: BTreeMap<(String, String, String), String>,
+}
+
+impl Netlist {
+    fn new(wires: BTreeMap<String, Mapping>) -> Self {
+        let by_inputs = wires
+            .iter()
+            .map(|(out, m)| (Self::key(m), out.clone()))
+            .collect();
+        Self { wires, by_inputs }
+    }
+
+    fn key(mapping: &Mapping) -> (String, String, String) {
+        let mut inputs = mapping.inputs();
+        inputs.sort();
+        (
+            inputs[0].to_string(),
+            mapping.op_name().to_string(),
+            inputs.get(1).unwrap_or(&"").to_string(),
+        )
+    }
+
+    fn gate(&self, a: &str, op: &str, b: &str) -> Option<&String> {
+        let (a, b) = if a < b { (a, b) } else { (b, a) };
+        self.by_inputs
+            .get(&(a.to_string(), op.to_string(), b.to_string()))
+    }
+
+    fn expect_gate(&self, a: &str, op: &str, b: &str) -> Result<String> {
+        self.gate(a, op, b)
+            .cloned()
+            .context(format!("expected to find gate {} {} {}", a, op, b))
+    }
+
+    // Finds a gate of the given type reading `wire`, returning its output and other input
+    fn gate_reading(&self, wire: &str, op: &str) -> Option<(String, String)> {
+        self.wires.iter().find_map(|(out, m)| {
+            if m.op_name() != op {
+                return None;
+            }
+            m.matching_ab(wire)
+                .map(|other| (out.clone(), other.to_string()))
+        })
+    }
+
+    fn swap(&mut self, a: &str, b: &str) -> Result<()> {
+        let a_map = self
+            .wires
+            .remove(a)
+            .context(format!("no gate drives {}", a))?;
+        let b_map = self
+            .wires
+            .remove(b)
+            .context(format!("no gate drives {}", b))?;
+        self.by_inputs.insert(Self::key(&a_map), b.to_string());
+        self.by_inputs.insert(Self::key(&b_map), a.to_string());
+        self.wires.insert(b.to_string(), a_map);
+        self.wires.insert(a.to_string(), b_map);
+        Ok(())
+    }
+}
+
+fn bus_width(inputs: &BTreeMap<String, bool>, wires: &BTreeMap<String, Mapping>) -> Result<u64> {
+    let count = |prefix: char, names: &mut dyn Iterator<Item = &String>| {
+        names.filter(|w| w.starts_with(prefix)).count() as u64
+    };
+    let x = count('x', &mut inputs.keys());
+    let y = count('y', &mut inputs.keys());
+    let z = count('z', &mut wires.keys());
+
+    if x == 0 || x != y || z != x + 1 {
+        return Err(anyhow!(
+            "expected an adder with matching x/y buses and one extra z wire, got x={} y={} z={}",
+            x,
+            y,
+            z
         ));
+    }
+    Ok(x)
+}
+
+// The outcome of fixing one step of an AddUnit, either the wire it found or a swap that
+// needs to happen before the unit can be identified.
+enum Step {
+    Found(String),
+    Swap(String, String),
+}
+
+fn find_out_sum(netlist: &Netlist, carry: &str, raw_sum: &str, z: &str) -> Result<Step> {
+    if let Some(out_sum) = netlist.gate(carry, "XOR", raw_sum) {
+        if out_sum != z {
+            return Ok(Step::Swap(out_sum.clone(), z.to_string()));
+        }
+        return Ok(Step::Found(out_sum.clone()));
+    }
 
-        links
+    // One of our inputs is wrong so whichever of them still feeds an XOR tells us which
+    if let Some((_, other)) = netlist.gate_reading(carry, "XOR") {
+        return Ok(Step::Swap(raw_sum.to_string(), other));
+    }
+    if let Some((_, other)) = netlist.gate_reading(raw_sum, "XOR") {
+        return Ok(Step::Swap(carry.to_string(), other));
     }
+    Err(anyhow!("no sum XOR reads {} or {}", carry, raw_sum))
 }
 
-fn find(
-    target: &str,
-    t: GateType,
-    by_type: &BTreeMap<GateType, BTreeSet<(String, Mapping)>>,
-) -> Option<(String, String)> {
-    for (wire, map) in by_type.get(&t).unwrap() {
-        if let Some(other) = map.matching_ab(target) {
-            return Some((wire.clone(), other.to_string()));
+fn find_out_carry(netlist: &Netlist, inner_carry: &str, raw_carry: &str) -> Result<Step> {
+    if let Some(out_carry) = netlist.gate(inner_carry, "OR", raw_carry) {
+        return Ok(Step::Found(out_carry.clone()));
+    }
+
+    if let Some((_, other)) = netlist.gate_reading(inner_carry, "OR") {
+        return Ok(Step::Swap(raw_carry.to_string(), other));
+    }
+    if let Some((_, other)) = netlist.gate_reading(raw_carry, "OR") {
+        return Ok(Step::Swap(inner_carry.to_string(), other));
+    }
+    Err(anyhow!(
+        "no carry OR reads {} or {}",
+        inner_carry,
+        raw_carry
+    ))
+}
+
+/// Walks the ripple-carry chain from bit 0, identifying each AddUnit purely from how the
+/// gates connect. Whenever a unit doesn't fit we swap the outputs that would make it fit
+/// and try that bit again. Returns the repaired units and every wire that was swapped.
+fn repair_adder(netlist: &mut Netlist, width: u64) -> Result<(Vec<AddUnit>, Vec<String>)> {
+    let mut swapped = Vec::new();
+    let mut swap = |netlist: &mut Netlist, a: String, b: String| -> Result<()> {
+        netlist.swap(&a, &b)?;
+        swapped.push(a);
+        swapped.push(b);
+        // Every swap fixes a wire for good, so anything beyond this means we are stuck
+        if swapped.len() > netlist.wires.len() {
+            return Err(anyhow!("circuit can't be repaired into an adder"));
         }
+        Ok(())
+    };
+
+    // Bit 0 is a half adder, there is no carry in
+    let half_sum = netlist.expect_gate("x00", "XOR", "y00")?;
+    if half_sum != "z00" {
+        swap(netlist, half_sum, "z00".to_string())?;
+    }
+    let mut carry = netlist.expect_gate("x00", "AND", "y00")?;
+
+    let mut units: Vec<AddUnit> = Vec::new();
+    let mut idx = 1;
+    while idx < width {
+        let x = format!("x{:02}", idx);
+        let y = format!("y{:02}", idx);
+        let z = format!("z{:02}", idx);
+
+        let raw_sum = netlist.expect_gate(&x, "XOR", &y)?;
+        let raw_carry = netlist.expect_gate(&x, "AND", &y)?;
+        let out_sum = match find_out_sum(netlist, &carry, &raw_sum, &z)? {
+            Step::Found(out_sum) => out_sum,
+            Step::Swap(a, b) => {
+                // The swap may rename the previous unit's carry out
+                if a == carry || b == carry {
+                    carry = if a == carry { b.clone() } else { a.clone() };
+                    if let Some(prev) = units.last_mut() {
+                        prev.out_carry = carry.clone();
+                    }
+                }
+                swap(netlist, a, b)?;
+                continue;
+            }
+        };
+        let inner_carry = netlist.expect_gate(&carry, "AND", &raw_sum)?;
+        let out_carry = match find_out_carry(netlist, &inner_carry, &raw_carry)? {
+            Step::Found(out_carry) => out_carry,
+            Step::Swap(a, b) => {
+                swap(netlist, a, b)?;
+                continue;
+            }
+        };
+
+        units.push(AddUnit {
+            idx,
+            input_carry: carry,
+            raw_carry,
+            raw_sum,
+            inner_carry,
+            out_sum,
+            out_carry: out_carry.clone(),
+        });
+        carry = out_carry;
+        idx += 1;
     }
 
+    let last_z = format!("z{:02}", width);
+    if carry != last_z {
+        swap(netlist, carry, last_z)?;
+    }
+
+    swapped.sort();
+    Ok((units, swapped))
+}
+
+/// Inputs to check an adder with: every single bit on its own and on both buses, carries
+/// rippling up from every position, plus some pseudo random pairs.
+fn test_vectors(width: u64) -> Vec<(u128, u128)> {
+    let mask: u128 = (1 << width) - 1;
+    let mut vectors = vec![
+        (0, 0),
+        (mask, 0),
+        (0, mask),
+        (mask, mask),
+        (mask, 1),
+        (1, mask),
+    ];
+    for bit in 0..width {
+        vectors.push((1 << bit, 0));
+        vectors.push((0, 1 << bit));
+        vectors.push((1 << bit, 1 << bit));
+        vectors.push((mask >> bit, 1));
+    }
+
+    // xorshift, we only need something that isn't structured
+    let mut state: u64 = 0x2545f4914f6cdd1d;
+    let mut next_u64 = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut next = || (((next_u64() as u128) << 64) | next_u64() as u128) & mask;
+    for _ in 0..32 {
+        vectors.push((next(), next()));
+    }
+    vectors
+}
+
+/// The lowest z bit that is wrong for any of the vectors, or None when z = x + y for all
+/// of them.
+fn first_broken_bit(
+    wires: &BTreeMap<String, Mapping>,
+    width: u64,
+    vectors: &[(u128, u128)],
+) -> Result<Option<u64>> {
+    broken_bit_above(wires, width, vectors, 0)
+}
+
+/// Like `first_broken_bit` but gives up as soon as a bit at or below `floor` is wrong,
+/// returning that bit, for callers that only care whether the lowest one is above it.
+fn broken_bit_above(
+    wires: &BTreeMap<String, Mapping>,
+    width: u64,
+    vectors: &[(u128, u128)],
+    floor: u64,
+) -> Result<Option<u64>> {
+    let inputs: Vec<String> = (0..width)
+        .flat_map(|bit| [format!("x{:02}", bit), format!("y{:02}", bit)])
+        .collect();
+    let mut circuit = Circuit::new(wires, &inputs)?;
+
+    let mut broken = None;
+    for (x, y) in vectors {
+        circuit.set_bus('x', *x)?;
+        circuit.set_bus('y', *y)?;
+        circuit.run();
+        let wrong = circuit.bus('z')? ^ (x + y);
+        if wrong != 0 {
+            let bit = wrong.trailing_zeros() as u64;
+            broken = Some(std::cmp::min(broken.unwrap_or(bit), bit));
+            if bit <= floor {
+                break;
+            }
+        }
+    }
+    Ok(broken)
+}
+
+// The highest x/y bit feeding into each gate, used to find the gates around a broken bit
+fn gate_levels(wires: &BTreeMap<String, Mapping>) -> BTreeMap<String, u64> {
+    let inputs: BTreeSet<String> = wires
+        .values()
+        .flat_map(|m| m.inputs())
+        .filter(|w| !wires.contains_key(*w))
+        .map(|w| w.to_string())
+        .collect();
+    let input_level = |wire: &str| {
+        bus_bit(wire, 'x')
+            .or_else(|| bus_bit(wire, 'y'))
+            .unwrap_or(0) as u64
+    };
+
+    let Ok(circuit) = Circuit::new(wires, &inputs) else {
+        // There's a cycle so there's no topological order, instead relax every gate until
+        // nothing changes
+        let mut levels: BTreeMap<String, u64> = wires.keys().map(|w| (w.clone(), 0)).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (out, mapping) in wires {
+                for wire in mapping.inputs() {
+                    let level = levels
+                        .get(wire)
+                        .copied()
+                        .unwrap_or_else(|| input_level(wire));
+                    if level > levels[out] {
+                        levels.insert(out.clone(), level);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        return levels;
+    };
+
+    let mut levels = vec![0; circuit.names.len()];
+    for idx in &circuit.inputs {
+        levels[*idx] = input_level(&circuit.names[*idx]);
+    }
+    // Gates are in topological order so their inputs always have a level already
+    for gate in &circuit.gates {
+        levels[gate.out] = std::cmp::max(levels[gate.a], levels[gate.b]);
+    }
+
+    circuit
+        .gates
+        .iter()
+        .map(|gate| (circuit.names[gate.out].clone(), levels[gate.out]))
+        .collect()
+}
+
+// Follows the gates feeding `wire` until one of them comes round again, returning that loop
+fn find_cycle(
+    wires: &BTreeMap<String, Mapping>,
+    wire: &str,
+    path: &mut Vec<String>,
+    done: &mut BTreeSet<String>,
+) -> Option<Vec<String>> {
+    if let Some(pos) = path.iter().position(|w| w == wire) {
+        return Some(path[pos..].to_vec());
+    }
+    let mapping = wires.get(wire)?;
+    if done.contains(wire) {
+        return None;
+    }
+    path.push(wire.to_string());
+    for input in mapping.inputs() {
+        if let Some(cycle) = find_cycle(wires, input, path, done) {
+            return Some(cycle);
+        }
+    }
+    path.pop();
+    done.insert(wire.to_string());
     None
 }
 
-pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<String>, anyhow::Error> {
+// Where a broken adder first goes wrong
+enum Fault {
+    // The lowest z bit that comes out wrong
+    Bit(u64),
+    // Gates feeding into themselves so nothing can be simulated, and the lowest level of
+    // any gate in the loop
+    Cycle(Vec<String>, u64),
+}
+
+fn find_fault(
+    wires: &BTreeMap<String, Mapping>,
+    width: u64,
+    vectors: &[(u128, u128)],
+) -> Result<Option<Fault>> {
+    match first_broken_bit(wires, width, vectors) {
+        Ok(broken) => Ok(broken.map(Fault::Bit)),
+        Err(err) => match err.downcast_ref::<SimError>() {
+            Some(SimError::Cycle(wire)) => {
+                let cycle = find_cycle(wires, wire, &mut Vec::new(), &mut BTreeSet::new())
+                    .context(format!("expected a cycle feeding {}", wire))?;
+                let levels = gate_levels(wires);
+                let level = cycle.iter().map(|w| levels[w]).min().unwrap_or(0);
+                Ok(Some(Fault::Cycle(cycle, level)))
+            }
+            _ => Err(err),
+        },
+    }
+}
+
+// The pairs of wires worth swapping to fix `fault`, picked from the gates feeding the bits
+// around it plus the broken z wire itself and whatever it reads, so a z wire swapped over
+// from far away is still found. A cycle is treated like a broken bit at its lowest level,
+// with each swap moving one of the cycle's own wires.
+fn candidate_swaps(netlist: &Netlist, fault: &Fault) -> Vec<(String, String)> {
+    let (broken, cycle) = match fault {
+        Fault::Bit(broken) => (*broken, &[][..]),
+        Fault::Cycle(cycle, level) => (*level, &cycle[..]),
+    };
+
+    let mut candidates: BTreeSet<String> = gate_levels(&netlist.wires)
+        .into_iter()
+        .filter(|(_, level)| level + 1 >= broken && *level <= broken + 1)
+        .map(|(wire, _)| wire)
+        .collect();
+    candidates.extend(cycle.iter().cloned());
+    let z = format!("z{:02}", broken);
+    if let Some(mapping) = netlist.wires.get(&z) {
+        for wire in mapping.inputs() {
+            if netlist.wires.contains_key(wire) {
+                candidates.insert(wire.to_string());
+            }
+        }
+        candidates.insert(z);
+    }
+    let candidates: Vec<String> = candidates.into_iter().collect();
+
+    let mut pairs = Vec::new();
+    for (idx, a) in candidates.iter().enumerate() {
+        for b in &candidates[idx + 1..] {
+            // Only a swap moving one of its wires can break a cycle
+            if cycle.is_empty() || cycle.contains(a) || cycle.contains(b) {
+                pairs.push((a.clone(), b.clone()));
+            }
+        }
+    }
+    pairs
+}
+
+// Depth first search for at most `budget` swaps. Each swap has to make progress on the
+// current fault, either pushing the lowest broken bit higher or breaking a cycle.
+fn search_swaps(
+    netlist: &mut Netlist,
+    width: u64,
+    vectors: &[(u128, u128)],
+    budget: usize,
+    swaps: &mut Vec<(String, String)>,
+) -> Result<bool> {
+    let Some(fault) = find_fault(&netlist.wires, width, vectors)? else {
+        return Ok(true);
+    };
+    if budget == 0 {
+        return Ok(false);
+    }
+
+    for (a, b) in candidate_swaps(netlist, &fault) {
+        netlist.swap(&a, &b)?;
+        // Swaps that introduce a cycle fail to simulate and are skipped
+        let floor = match fault {
+            Fault::Bit(broken) => broken,
+            Fault::Cycle(..) => 0,
+        };
+        let improved = match (
+            &fault,
+            broken_bit_above(&netlist.wires, width, vectors, floor),
+        ) {
+            (_, Ok(None)) => true,
+            (Fault::Bit(broken), Ok(Some(bit))) => bit > *broken,
+            // Lower bits may still be broken by other swaps, so any way out of the loop will do
+            (Fault::Cycle(..), Ok(Some(_))) => true,
+            (_, Err(_)) => false,
+        };
+        if improved {
+            swaps.push((a.clone(), b.clone()));
+            if search_swaps(netlist, width, vectors, budget - 1, swaps)? {
+                return Ok(true);
+            }
+            swaps.pop();
+        }
+        netlist.swap(&a, &b)?;
+    }
+    Ok(false)
+}
+
+/// Repairs the adder by trying swaps and simulating the result rather than by matching the
+/// structure, so it doesn't depend on how the adder's gates are laid out. Returns the
+/// fewest swaps this search finds, up to `max_swaps` pairs.
+///
+/// This is a heuristic rather than a true minimum: only swaps near the lowest broken bit
+/// are tried (see `candidate_swaps`) and each one has to fix that bit on its own, so a fix
+/// needing two swaps to land together is missed. Swaps that don't change what the adder
+/// computes are never needed, so the answer can be smaller than the structural one.
+fn repair_by_simulation(
+    netlist: &mut Netlist,
+    width: u64,
+    max_swaps: usize,
+) -> Result<Vec<String>> {
+    if width >= 128 {
+        return Err(anyhow!("can't simulate a {} bit adder in a u128", width));
+    }
+    let vectors = test_vectors(width);
+
+    // Iterative deepening so the first answer found uses the fewest swaps
+    for budget in 0..=max_swaps {
+        let mut swaps = Vec::new();
+        if search_swaps(netlist, width, &vectors, budget, &mut swaps)? {
+            let mut swapped: Vec<String> = swaps.into_iter().flat_map(|(a, b)| [a, b]).collect();
+            swapped.sort();
+            return Ok(swapped);
+        }
+    }
+    Err(anyhow!(
+        "no set of up to {} swaps fixes the adder",
+        max_swaps
+    ))
+}
+
+/// Renders the netlist as a Graphviz DOT graph with one node per gate, named after the
+/// wire it drives. Inputs are ranked at the top, outputs at the bottom and any wire in
+/// `broken` is outlined in red.
+fn to_dot(wires: &BTreeMap<String, Mapping>, broken: &[String]) -> String {
+    let mut inputs = BTreeSet::new();
+    for mapping in wires.values() {
+        for wire in mapping.inputs() {
+            if !wires.contains_key(wire) {
+                inputs.insert(wire);
+            }
+        }
+    }
+    let outputs: Vec<&String> = wires.keys().filter(|w| w.starts_with('z')).collect();
+
+    let mut out = Vec::new();
+    out.push("digraph netlist {".to_string());
+    out.push("  node [style=filled, fillcolor=white];".to_string());
+    out.push(format!(
+        "  {{ rank=source; {} }}",
+        inputs.iter().map(|w| format!("{};", w)).join(" ")
+    ));
+    out.push(format!(
+        "  {{ rank=sink; {} }}",
+        outputs.iter().map(|w| format!("{};", w)).join(" ")
+    ));
+
+    for wire in &inputs {
+        out.push(format!("  {} [shape=box];", wire));
+    }
+    for (wire, mapping) in wires {
+        let highlight = if broken.contains(wire) {
+            ", color=red, penwidth=3"
+        } else {
+            ""
+        };
+        out.push(format!(
+            "  {} [label=\"{}\\n{}\", fillcolor={}{}];",
+            wire,
+            wire,
+            mapping.op_name(),
+            mapping.gate_type().map_or("white", |t| t.colour()),
+            highlight
+        ));
+    }
+    for (wire, mapping) in wires {
+        for input in mapping.inputs() {
+            out.push(format!("  {} -> {};", input, wire));
+        }
+    }
+    out.push("}".to_string());
+    out.join("\n")
+}
+
+fn parse(input: &str) -> Result<(BTreeMap<String, bool>, BTreeMap<String, Mapping>)> {
     let (inits, maps_str) = input
         .split_once("\n\n")
         .context("Failed to split sections")?;
@@ -361,400 +977,99 @@ pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<String>, anyho
     }
 
     let mut wires = BTreeMap::new();
-    let mut wire_lookup = BTreeMap::new();
-    let mut type_map = BTreeMap::new();
-    let mut raw_carries = BTreeMap::new();
-    let mut raw_sums = BTreeMap::new();
-    let mut by_type: BTreeMap<GateType, BTreeSet<(String, Mapping)>> = BTreeMap::new();
     for map in maps_str.lines() {
         let (out, m) = Mapping::parse(map).context("failed to parse mapping")?;
+        wires.insert(out, m);
+    }
 
-        let (a, b) = m.ab();
-        let (a, b) = if a < b { (a, b) } else { (b, a) };
-        wire_lookup.insert(
-            (a.to_string(), m.op_name().to_string(), b.to_string()),
-            out.clone(),
-        );
+    Ok((inputs, wires))
+}
 
-        let (t, maybe_idx) = (&m).into();
-        if let Some(idx) = maybe_idx {
-            match t {
-                GateType::RawCarry => {
-                    raw_carries.insert(idx, out.clone());
-                }
-                GateType::RawSum => {
-                    raw_sums.insert(idx, out.clone());
-                }
-                _ => {}
-            }
-        }
+/// The gates from the input and the output wires that have to be swapped back to repair it.
+fn repair(input: &str) -> Result<(BTreeMap<String, Mapping>, Vec<String>)> {
+    let (inputs, wires) = parse(input)?;
+    let width = bus_width(&inputs, &wires)?;
+
+    // The puzzle always has four swaps
+    let swapped = reconcile(
+        repair_structure(&mut Netlist::new(wires.clone()), width),
+        repair_by_simulation(&mut Netlist::new(wires.clone()), width, 4),
+    )?;
+    Ok((wires, swapped))
+}
 
-        by_type
-            .entry(t.clone())
-            .or_default()
-            .insert((out.clone(), m.clone()));
-        type_map.insert(out.clone(), t);
-        wires.insert(out, m);
+pub fn part_two(input: &str, _run_type: RunType) -> Result<Option<String>, anyhow::Error> {
+    let (_, swapped) = repair(input)?;
+    Ok(Some(swapped.join(",")))
+}
+
+/// Part two from the command line. `--dot <file>` writes the netlist with the swapped
+/// wires highlighted, e.g. `--dot netlist.dot` then `dot -Tsvg netlist.dot`.
+fn cli_part_two(input: &str, run_type: RunType) -> Result<Option<String>, anyhow::Error> {
+    let args = Args::from_env();
+    let Some(path) = args.value("dot")? else {
+        return part_two(input, run_type);
+    };
+
+    let (wires, swapped) = repair(input)?;
+    std::fs::write(path, to_dot(&wires, &swapped))
+        .context(format!("failed to write DOT graph {:?}", path))?;
+    Ok(Some(swapped.join(",")))
+}
+
+/// Cross checks the structural and simulated repairs, which have to agree whenever they
+/// both find one. A netlist that isn't a textbook adder can only be repaired by simulating.
+fn reconcile(
+    structural: Result<Vec<String>>,
+    simulated: Result<Vec<String>>,
+) -> Result<Vec<String>> {
+    match (structural, simulated) {
+        (Ok(structural), Ok(simulated)) if structural == simulated => Ok(structural),
+        (Ok(structural), Ok(simulated)) => Err(anyhow!(
+            "structural repair swapped {} but simulation swapped {}",
+            structural.join(","),
+            simulated.join(",")
+        )),
+        (Ok(structural), Err(err)) => Err(err.context(format!(
+            "structural repair swapped {} but simulation found no fix",
+            structural.join(",")
+        ))),
+        (Err(_), Ok(simulated)) => Ok(simulated),
+        (Err(structural), Err(simulated)) => {
+            Err(simulated.context(format!("structural repair failed: {:#}", structural)))
+        }
     }
-    let mut cache = BTreeMap::new();
-    resolve_wire(&"z13", &wires, &inputs, &mut cache, 0, false)
-        .context("failed to resolve wire")?;
-    println!("{:?}", cache);
-    resolve_wire(&"z14", &wires, &inputs, &mut cache, 0, true).context("failed to resolve wire")?;
-    println!("{:?}", cache);
-    resolve_wire(&"z15", &wires, &inputs, &mut cache, 0, true).context("failed to resolve wire")?;
-    println!("{:?}", cache);
-
-    // let mut cache = BTreeMap::new();
-    // resolve_wire(&"z02", &wires, &inputs, &mut cache, 0, true).context("failed to resolve wire")?;
-    // println!("{:?}", cache);
-
-    // let mut cache = BTreeMap::new();
-    // resolve_wire(&"z03", &wires, &inputs, &mut cache, 0, true).context("failed to resolve wire")?;
-    // println!("{:?}", cache);
-
-    let mut units = Vec::new();
-    let mut rewrite_carry = Vec::new();
-    let mut prev_carry = "rtc".to_string();
-    // let mut raw_half_adders = BTreeMap::new();
-    for i in 2..45 {
-        println!("prev_carry={}", prev_carry);
-        let raw_carry = raw_carries.get(&i).unwrap();
-        println!("raw_carry={}", raw_carry);
-        let raw_sum = raw_sums.get(&i).unwrap();
-        println!("raw_sum={}", raw_sum);
-        // raw_half_adders.insert(i, (c, s));
-        let inner_carry = match find(&prev_carry, GateType::InnerCarry, &by_type) {
-            Some(c) => c.0,
-            None => {
-                panic!("failed!");
-                let (wire, other) = find(&raw_sum, GateType::InnerCarry, &by_type).unwrap();
-                rewrite_carry.push((i, prev_carry, other.clone()));
-                prev_carry = other;
-                wire
-            }
-        };
-        // println!("inner_carry={}", inner_carry);
-        let out_sum = find(&prev_carry, GateType::OutSum, &by_type).unwrap().0;
-        // println!("out_sum={}", out_sum);
-        let out_carry = match find(&inner_carry, GateType::OutCarry, &by_type) {
-            Some(o) => o.0,
-            None => {
-                let mut cache = BTreeMap::new();
-                resolve_wire(&prev_carry, &wires, &inputs, &mut cache, 0, false)
-                    .context("failed to resolve wire")?;
-                resolve_wire(&format!("z{:02}", i), &wires, &inputs, &mut cache, 0, true)
-                    .context("failed to resolve wire")?;
-                println!("{:?}", cache);
-                panic!("failed!");
-                // let (wire, other) = find(&raw_carry, GateType::OutCarry, &by_type).unwrap();
-                // rewrite_carry.push((i, prev_carry, other.clone()));
-                // prev_carry = other;
-                // wire
-            }
-        };
-        let unit = AddUnit {
-            idx: i as u64,
-            input_carry: prev_carry.clone(),
-            raw_carry: raw_carry.clone(),
-            raw_sum: raw_sum.clone(),
-            inner_carry,
-            out_sum,
-            out_carry: out_carry.clone(),
-        };
-        println!("{}={:?}", i, unit);
-        units.push(unit);
-        prev_carry = out_carry;
-    }
-
-    let mut broken = BTreeSet::new();
-    for unit in units {
-        for (mapping, out) in unit.mappings() {
-            let (a, b) = mapping.ab();
-            let (a, b) = if a < b { (a, b) } else { (b, a) };
-            let op = mapping.op_name();
-            if let Some(input_wire) =
-                wire_lookup.get(&(a.to_string(), op.to_string(), b.to_string()))
-            {
-                if input_wire != &out {
-                    println!("{} {} {} -> {} != {}", a, op, b, out, input_wire);
-                    broken.insert(input_wire.clone());
-                    broken.insert(out);
-                } else {
-                    // println!("{} {} {} -> {}", a, op, b, out);
-                }
-            } else {
-                println!("{}: {} {} {} -> {}", unit.idx, a, op, b, out);
-                println!("Failed to find link!");
+}
+
+/// Repairs a textbook adder by matching its structure and then cross checks the result by
+/// simulating it.
+fn repair_structure(netlist: &mut Netlist, width: u64) -> Result<Vec<String>> {
+    let (units, swapped) = repair_adder(netlist, width)?;
+
+    // Sanity check that the repaired netlist really contains every gate we identified
+    for unit in &units {
+        for (gate_type, mapping, out) in unit.gates() {
+            if netlist.by_inputs.get(&Netlist::key(&mapping)) != Some(&out) {
+                return Err(anyhow!(
+                    "bit {} {:?} gate {:?} doesn't drive {}",
+                    unit.idx,
+                    gate_type,
+                    mapping,
+                    out
+                ));
             }
         }
-        if !unit.out_sum.starts_with("z") {
-            broken.insert(unit.out_sum.clone());
-            broken.insert(format!("z{:02}", unit.idx));
-        }
-        if unit.raw_carry.starts_with("z") {
-            broken.insert(unit.raw_carry.clone());
-        }
-        if unit.raw_sum.starts_with("z") {
-            broken.insert(unit.raw_sum.clone());
-        }
-        if unit.inner_carry.starts_with("z") {
-            broken.insert(unit.inner_carry.clone());
-        }
-        if unit.out_carry.starts_with("z") {
-            broken.insert(unit.out_carry.clone());
-        }
-    }
-
-    for (_, a, b) in &rewrite_carry {
-        broken.insert(a.clone());
-        broken.insert(b.clone());
-    }
-    println!("{:?}", rewrite_carry);
-    println!("{:?} {}", broken, broken.len());
-
-    let mut out: Vec<String> = broken.into_iter().collect();
-    out.sort();
-
-    Ok(Some(out.join(",")))
-
-    // let mut type_map = BTreeMap::new();
-    // let mut raw_carry = BTreeMap::new();
-    // let mut raw_sums = BTreeMap::new();
-    // let mut inner_carry = BTreeMap::new();
-    // let mut out_carry = BTreeMap::new();
-    // let mut out_sum = BTreeMap::new();
-
-    // for (wire, mapping) in &wires {
-    //     if let Mapping::And { a, b } = mapping {
-    //         if let Some((x, y)) = extract_xy(a, b) {
-    //             assert!(x == y);
-    //             raw_carry.insert(x, wire.clone());
-    //             assert!(!type_map.contains_key(wire));
-    //             type_map.insert(wire.clone(), GateTypeIdx::RawCarry(x));
-    //         }
-    //     }
-    //     if let Mapping::Xor { a, b } = mapping {
-    //         if let Some((x, y)) = extract_xy(a, b) {
-    //             assert!(x == y);
-    //             raw_sums.insert(x, wire.clone());
-    //             assert!(!type_map.contains_key(wire));
-    //             type_map.insert(wire.clone(), GateTypeIdx::RawSum(x));
-    //         }
-    //     }
-    // }
-    // for (wire, mapping) in &wires {
-    //     if let Mapping::And { a, b } = mapping {
-    //         // If we find an AND and it's not a raw carry it must be an InnerCarry
-    //         if type_map.contains_key(wire) {
-    //             continue;
-    //         }
-
-    //         let (_, idx) = if let Some(GateTypeIdx::RawSum(idx)) = type_map.get(a) {
-    //             (a, idx)
-    //         } else if let Some(GateTypeIdx::RawSum(idx)) = type_map.get(b) {
-    //             (b, idx)
-    //         } else {
-    //             println!(
-    //                 "{}={:?} should be a valid InnerCarry but isn't",
-    //                 wire, mapping
-    //             );
-    //             continue;
-    //         };
-    //         inner_carry.insert(*idx, wire.clone());
-    //         assert!(!type_map.contains_key(wire));
-    //         type_map.insert(wire.clone(), GateTypeIdx::InnerCarry(*idx));
-    //     }
-    // }
-    // println!("raw_carry={:?}", raw_carry);
-    // println!("raw_sums={:?}", raw_sums);
-    // println!("inner_carry={:?}", inner_carry);
-    // println!("{:?}", type_map);
-
-    // for (wire, mapping) in &wires {
-    //     // All ORs should be for outcarry
-    //     if let Mapping::Or { a, b } = mapping {
-    //         assert!(!type_map.contains_key(wire));
-
-    //         let a_type = if let Some(a_type) = type_map.get(a) {
-    //             a_type
-    //         } else {
-    //             println!("{} a ({}) is unknown!", wire, a);
-    //             continue;
-    //         };
-    //         let b_type = if let Some(b_type) = type_map.get(b) {
-    //             b_type
-    //         } else {
-    //             println!("{} b ({}) is unknown!", wire, b);
-    //             continue;
-    //         };
-    //         let idx = match (a_type, b_type) {
-    //             (GateTypeIdx::InnerCarry(inner_idx), GateTypeIdx::RawCarry(raw_idx))
-    //             | (GateTypeIdx::RawCarry(raw_idx), GateTypeIdx::InnerCarry(inner_idx)) => {
-    //                 assert!(raw_idx == inner_idx);
-    //                 raw_idx
-    //             }
-    //             _ => {
-    //                 println!(
-    //                     "{} got unexpected types {:?} and {:?}",
-    //                     wire, a_type, b_type
-    //                 );
-    //                 continue;
-    //             }
-    //         };
-
-    //         out_carry.insert(*idx, wire.clone());
-    //         assert!(!type_map.contains_key(wire));
-    //         type_map.insert(wire.clone(), GateTypeIdx::OutCarry(*idx));
-    //     }
-    // }
-
-    // for (wire, mapping) in &wires {
-    //     // All ORs should be for outcarry
-    //     if let Mapping::Xor { a, b } = mapping {
-    //         if type_map.contains_key(wire) {
-    //             continue;
-    //         }
-
-    //         let a_type = if let Some(a_type) = type_map.get(a) {
-    //             a_type
-    //         } else {
-    //             println!("{} a ({}) is unknown!", wire, a);
-    //             continue;
-    //         };
-    //         let b_type = if let Some(b_type) = type_map.get(b) {
-    //             b_type
-    //         } else {
-    //             println!("{} b ({}) is unknown!", wire, b);
-    //             continue;
-    //         };
-    //         let idx = match (a_type, b_type) {
-    //             (GateTypeIdx::OutCarry(carry_idx), GateTypeIdx::RawSum(sum_idx))
-    //             | (GateTypeIdx::RawSum(sum_idx), GateTypeIdx::OutCarry(carry_idx)) => {
-    //                 assert!(carry_idx + 1 == *sum_idx);
-    //                 sum_idx
-    //             }
-    //             _ => {
-    //                 println!(
-    //                     "{} got unexpected types {:?} and {:?}",
-    //                     wire, a_type, b_type
-    //                 );
-    //                 continue;
-    //             }
-    //         };
-
-    //         out_sum.insert(*idx, wire.clone());
-    //         assert!(!type_map.contains_key(wire));
-    //         type_map.insert(wire.clone(), GateTypeIdx::OutSum(*idx));
-
-    //         println!(
-    //             "{}={:?}: {:?} {:?}",
-    //             wire,
-    //             mapping,
-    //             type_map.get(a),
-    //             type_map.get(b)
-    //         );
-    //     }
-    // }
-
-    // for (wire, mapping) in &wires {
-    //     if type_map.contains_key(wire) {
-    //         continue;
-    //     }
-    //     println!("Unmapped: {}={:?}", wire, mapping);
-    // }
-    // for i in 2..45 {
-    //     let wire = format!("z{:02}", i);
-    //     let gate_type = type_map.get(&wire);
-    //     if let Some(GateTypeIdx::OutSum(idx)) = gate_type {
-    //         if *idx == i {
-    //             continue;
-    //         }
-    //         println!(
-    //             "{} ({:?}) end is correct type but bad index! {}",
-    //             wire, gate_type, idx
-    //         );
-    //     } else {
-    //         println!("{} ({:?}) end is invalid!", wire, gate_type);
-    //     }
-    // }
-    // for i in 2..45 {
-    //     let elements = [
-    //         raw_carry.get(&i),
-    //         raw_sums.get(&i),
-    //         inner_carry.get(&i),
-    //         out_carry.get(&i),
-    //         out_sum.get(&i),
-    //     ];
-    //     let mut invalid = elements.iter().any(|e| e.is_none());
-    //     if let Some(s) = out_sum.get(&i) {
-    //         invalid |= !s.starts_with('z');
-    //     }
-    //     if invalid {
-    //         println!(
-    //             "S: {} {:?} {:?} {:?} {:?} {:?}",
-    //             i,
-    //             raw_carry.get(&i),
-    //             raw_sums.get(&i),
-    //             inner_carry.get(&i),
-    //             out_carry.get(&i),
-    //             out_sum.get(&i),
-    //         );
-    //     }
-    //     let wire = format!("z{:02}", i);
-    //     if let Some(GateTypeIdx::OutSum(idx)) = type_map.get(&wire) {
-    //         if *idx != i {
-    //             println!("end is correct type but bad index! {}", idx);
-    //         }
-    //     } else {
-    //         println!("end is invalid!");
-    //     }
-    // }
-
-    // let mut out_carry = BTreeMap::new();
-
-    // for wire in wires.keys() {
-    // for i in 2..45 {
-    //     let mut cache = BTreeMap::new();
-    //     let wire = format!("z{:02}", i);
-    //     println!("{:?}", wire);
-    //     resolve_wire(&wire, &wires, &mut cache, 0, false).context("failed to resolve wire")?;
-    //     let mut inputs = Vec::new();
-    //     for key in cache.keys() {
-    //         if key.starts_with("x") || key.starts_with("y") || key.starts_with("z") {
-    //             inputs.push(key);
-    //         }
-    //     }
-    //     // println!("{}, {:?}", wire, inputs);
-    //     is_adder(i, &wire, &wires);
-    // }
-
-    // let mut cache = BTreeMap::new();
-    // resolve_wire(&"z06", &wires, &inputs, &mut cache, 0, true).context("failed to resolve wire")?;
-    // println!("{:?}", cache);
-    // let mut cache = BTreeMap::new();
-    // for wire in wires.keys() {
-    //     if let Some(_) = wire.strip_prefix('z') {
-    //         resolve_wire(wire, &wires, &mut cache, 0, false).context("failed to resolve wire")?;
-    //     }
-    // }
-
-    // is_adder(1, "z01", &wires);
-    // println!("{} vs {}", cache.len(), wires.len());
-
-    // let mut out = 0;
-    // for wire in wires.keys() {
-    //     if let Some(wire_num) = wire.strip_prefix('z') {
-    //         if resolve_wire(wire, &wires, &mut cache).context("failed to resolve wire")? {
-    //             let v: u64 = wire_num.parse().context("failed to parse after z")?;
-    //             out += 1 << v;
-    //         }
-    //     }
-    // }
-    //
-
-    // hdt <-> z05
-    // Ok(Some(out))
+        if unit.out_sum != format!("z{:02}", unit.idx) {
+            return Err(anyhow!("bit {} sum drives {}", unit.idx, unit.out_sum));
+        }
+    }
+
+    if width < 128 {
+        if let Some(bit) = first_broken_bit(&netlist.wires, width, &test_vectors(width))? {
+            return Err(anyhow!("repaired adder still gets bit {} wrong", bit));
+        }
+    }
+    Ok(swapped)
 }
 
 #[cfg(test)]
@@ -772,12 +1087,262 @@ mod tests_day_24 {
     }
 
     #[test]
-    fn test_part_two() -> anyhow::Result<()> {
-        let expected = None;
+    fn test_part_two() {
+        // The example isn't an adder so there is nothing to repair
         let input = &advent_of_code::template::read_file_part("examples", DAY, 2);
-        assert!(expected.is_none() || !input.is_empty(), "example 2 empty!");
-        let result = part_two(input, RunType::Example)?;
-        assert_eq!(result, expected);
+        assert!(!input.is_empty(), "example 2 empty!");
+        let err = part_two(input, RunType::Example).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected an adder with matching x/y buses and one extra z wire, got x=5 y=5 z=13"
+        );
+    }
+
+    // A textbook ripple-carry adder with the outputs of each pair of wires swapped
+    fn adder_input(width: u64, swaps: &[(&str, &str)]) -> String {
+        let mut inits = Vec::new();
+        let mut gates = Vec::new();
+        for i in 0..width {
+            inits.push(format!("x{:02}: {}", i, i % 2));
+            inits.push(format!("y{:02}: {}", i, (i / 2) % 2));
+
+            let (x, y) = (format!("x{:02}", i), format!("y{:02}", i));
+            let carry_out = if i + 1 == width {
+                format!("z{:02}", width)
+            } else {
+                format!("o{:02}", i)
+            };
+            if i == 0 {
+                gates.push((format!("{} XOR {}", x, y), "z00".to_string()));
+                gates.push((format!("{} AND {}", y, x), carry_out));
+                continue;
+            }
+            let carry_in = format!("o{:02}", i - 1);
+            gates.push((format!("{} XOR {}", y, x), format!("s{:02}", i)));
+            gates.push((format!("{} AND {}", x, y), format!("c{:02}", i)));
+            gates.push((format!("{} XOR s{:02}", carry_in, i), format!("z{:02}", i)));
+            gates.push((format!("s{:02} AND {}", i, carry_in), format!("i{:02}", i)));
+            gates.push((format!("c{:02} OR i{:02}", i, i), carry_out));
+        }
+
+        for (_, out) in gates.iter_mut() {
+            for (a, b) in swaps {
+                if out == a {
+                    *out = b.to_string();
+                } else if out == b {
+                    *out = a.to_string();
+                }
+            }
+        }
+        let gates: Vec<String> = gates
+            .into_iter()
+            .map(|(g, out)| format!("{} -> {}", g, out))
+            .collect();
+        format!("{}\n\n{}\n", inits.join("\n"), gates.join("\n"))
+    }
+
+    #[test]
+    fn test_part_two_repairs() -> anyhow::Result<()> {
+        let input = adder_input(45, &[]);
+        assert_eq!(part_two(&input, RunType::Real)?, Some(String::new()));
+
+        let input = adder_input(
+            45,
+            &[
+                ("s05", "c05"),
+                ("z10", "i10"),
+                ("z20", "o20"),
+                ("z30", "c30"),
+            ],
+        );
+        assert_eq!(
+            part_two(&input, RunType::Real)?,
+            Some("c05,c30,i10,o20,s05,z10,z20,z30".to_string())
+        );
+
+        let input = adder_input(45, &[("c07", "z07"), ("s12", "c12"), ("o40", "z41")]);
+        assert_eq!(
+            part_two(&input, RunType::Real)?,
+            Some("c07,c12,o40,s12,z07,z41".to_string())
+        );
+
+        let input = adder_input(8, &[("z00", "o00"), ("o06", "z08")]);
+        assert_eq!(
+            part_two(&input, RunType::Real)?,
+            Some("o00,o06,z00,z08".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_by_simulation() -> anyhow::Result<()> {
+        let input = adder_input(12, &[("s03", "c03"), ("z06", "o06"), ("i09", "z09")]);
+        let (inputs, wires) = parse(&input)?;
+        let width = bus_width(&inputs, &wires)?;
+
+        let mut netlist = Netlist::new(wires.clone());
+        let simulated = repair_by_simulation(&mut netlist, width, 4)?;
+        assert_eq!(simulated, vec!["c03", "i09", "o06", "s03", "z06", "z09"]);
+
+        let mut netlist = Netlist::new(wires);
+        assert_eq!(repair_structure(&mut netlist, width)?, simulated);
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_by_simulation_cross_bit() -> anyhow::Result<()> {
+        // Neither wire is driven from anywhere near the other's bit
+        let input = adder_input(12, &[("z03", "z08")]);
+        let (inputs, wires) = parse(&input)?;
+        let width = bus_width(&inputs, &wires)?;
+
+        let mut netlist = Netlist::new(wires.clone());
+        assert_eq!(
+            repair_by_simulation(&mut netlist, width, 4)?,
+            vec!["z03", "z08"]
+        );
+
+        let mut netlist = Netlist::new(wires);
+        assert_eq!(repair_structure(&mut netlist, width)?, vec!["z03", "z08"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_by_simulation_minimal() -> anyhow::Result<()> {
+        // Swapping the two carries into the OR doesn't change what the adder computes
+        let input = adder_input(8, &[("i04", "c04"), ("z05", "o05")]);
+        let (inputs, wires) = parse(&input)?;
+        let width = bus_width(&inputs, &wires)?;
+
+        let mut netlist = Netlist::new(wires.clone());
+        assert_eq!(
+            repair_by_simulation(&mut netlist, width, 4)?,
+            vec!["o05", "z05"]
+        );
+
+        // Matching the structure can't tell the two carries apart either, so both agree
+        assert_eq!(
+            part_two(&input, RunType::Real)?,
+            Some("o05,z05".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let swaps = |wires: &[&str]| -> Result<Vec<String>> {
+            Ok(wires.iter().map(|w| w.to_string()).collect())
+        };
+        assert_eq!(
+            reconcile(swaps(&["a", "b"]), swaps(&["a", "b"])).ok(),
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(
+            reconcile(Err(anyhow!("not an adder")), swaps(&["c", "d"])).ok(),
+            Some(vec!["c".to_string(), "d".to_string()])
+        );
+        assert_eq!(
+            reconcile(swaps(&["a", "b"]), swaps(&["c", "d"]))
+                .unwrap_err()
+                .to_string(),
+            "structural repair swapped a,b but simulation swapped c,d"
+        );
+        assert_eq!(
+            format!(
+                "{:#}",
+                reconcile(swaps(&["a", "b"]), Err(anyhow!("no swaps"))).unwrap_err()
+            ),
+            "structural repair swapped a,b but simulation found no fix: no swaps"
+        );
+        assert!(reconcile(Err(anyhow!("one")), Err(anyhow!("two"))).is_err());
+    }
+
+    #[test]
+    fn test_to_dot() -> anyhow::Result<()> {
+        let input = adder_input(2, &[("z01", "z02")]);
+        let (_, wires) = parse(&input)?;
+        let dot = to_dot(&wires, &["z01".to_string(), "z02".to_string()]);
+        let lines: Vec<&str> = dot.lines().collect();
+
+        assert_eq!(lines[0], "digraph netlist {");
+        assert_eq!(lines[2], "  { rank=source; x00; x01; y00; y01; }");
+        assert_eq!(lines[3], "  { rank=sink; z00; z01; z02; }");
+        assert!(lines.contains(&"  x00 [shape=box];"));
+        assert!(lines.contains(&"  s01 [label=\"s01\\nXOR\", fillcolor=palegreen];"));
+        assert!(lines.contains(&"  o00 [label=\"o00\\nAND\", fillcolor=lightblue];"));
+        assert!(lines.contains(
+            &"  z02 [label=\"z02\\nXOR\", fillcolor=mediumseagreen, color=red, penwidth=3];"
+        ));
+        assert!(
+            lines.contains(&"  z01 [label=\"z01\\nOR\", fillcolor=khaki, color=red, penwidth=3];")
+        );
+        assert!(lines.contains(&"  o00 -> z02;"));
+        assert_eq!(lines.last(), Some(&"}"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_circuit_gates() -> anyhow::Result<()> {
+        let input = "a: 1\nb: 0\n\n\
+            a NAND b -> z00\n\
+            a NOR b -> z01\n\
+            a XNOR b -> z02\n\
+            NOT a -> z03\n\
+            z03 XNOR b -> z04\n";
+        let (inputs, wires) = parse(input)?;
+        let mut circuit = Circuit::new(&wires, inputs.keys())?;
+        for (wire, value) in &inputs {
+            circuit.set(wire, *value)?;
+        }
+        circuit.run();
+        assert_eq!(circuit.bus('z')?, 0b10001);
+
+        assert_eq!(
+            circuit.set("z00", true),
+            Err(SimError::NotAnInput("z00".into()))
+        );
+        assert_eq!(part_one(input, RunType::Example)?, Some(0b10001));
+        Ok(())
+    }
+
+    #[test]
+    fn test_circuit_errors() -> anyhow::Result<()> {
+        let (inputs, wires) = parse("x00: 1\n\nx00 AND abc -> def\ndef OR x00 -> abc\n")?;
+        assert_eq!(
+            Circuit::new(&wires, inputs.keys()).err(),
+            Some(SimError::Cycle("abc".into()))
+        );
+
+        let (inputs, wires) = parse("x00: 1\n\nx00 AND y00 -> z00\n")?;
+        assert_eq!(
+            Circuit::new(&wires, inputs.keys()).err(),
+            Some(SimError::Undriven("y00".into()))
+        );
+
+        let (inputs, wires) = parse("x00: 1\nx01: 1\n\nNOT x01 -> z00\n")?;
+        let mut circuit = Circuit::new(&wires, inputs.keys())?;
+        assert_eq!(circuit.set_bus('x', 4), Err(SimError::BusOverflow('x', 4)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_circuit_wide_adder() -> anyhow::Result<()> {
+        let input = adder_input(100, &[]);
+        let (inputs, wires) = parse(&input)?;
+        let mut circuit = Circuit::new(&wires, inputs.keys())?;
+
+        let x = (1u128 << 100) - 1;
+        let y = 0x0001_2345_6789_abcd_ef01_2345_6789;
+        circuit.set_bus('x', x)?;
+        circuit.set_bus('y', y)?;
+        circuit.run();
+        assert_eq!(circuit.bus('z')?, x + y);
+
+        let input = adder_input(100, &[("z70", "i70"), ("s90", "c90")]);
+        assert_eq!(
+            part_two(&input, RunType::Real)?,
+            Some("c90,i70,s90,z70".to_string())
+        );
         Ok(())
     }
 }
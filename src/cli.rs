@@ -0,0 +1,115 @@
+//! Flags for the extra modes some days have, e.g. `cargo run --bin 18 -- --show`.
+//!
+//! Only the binary entry points read these. The `part_one`/`part_two` solvers never look at
+//! the process arguments so they give the same answer under `cargo test` as from the command
+//! line.
+
+use anyhow::{Context, Result};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Default)]
+pub struct Args {
+    args: Vec<String>,
+}
+
+impl Args {
+    /// The arguments the binary was run with, skipping the binary name
+    pub fn from_env() -> Self {
+        Self::new(std::env::args().skip(1))
+    }
+
+    pub fn new<I, S>(args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            args: args.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Whether `--name` was passed
+    pub fn flag(&self, name: &str) -> bool {
+        self.args.contains(&format!("--{}", name))
+    }
+
+    /// Every value passed as `--name <value>`, in the order they were given
+    pub fn values(&self, name: &str) -> Result<Vec<&str>> {
+        let flag = format!("--{}", name);
+        let mut values = Vec::new();
+        let mut args = self.args.iter();
+        while let Some(arg) = args.next() {
+            if *arg == flag {
+                let value = args
+                    .next()
+                    .with_context(|| format!("expected {} <value>", flag))?;
+                values.push(value.as_str());
+            }
+        }
+        Ok(values)
+    }
+
+    /// The value passed as `--name <value>`, the last one wins if it's given more than once
+    pub fn value(&self, name: &str) -> Result<Option<&str>> {
+        Ok(self.values(name)?.pop())
+    }
+
+    /// Parses the value passed as `--name <value>`
+    pub fn parse<T>(&self, name: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        anyhow::Error: From<T::Err>,
+    {
+        self.value(name)?
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(anyhow::Error::from)
+                    .with_context(|| format!("invalid value {:?} for --{}", value, name))
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests_cli {
+    use super::*;
+
+    fn args(line: &str) -> Args {
+        Args::new(line.split_whitespace())
+    }
+
+    #[test]
+    fn test_flag() {
+        let args = args("--show --size 7");
+        assert!(args.flag("show"));
+        assert!(args.flag("size"));
+        assert!(!args.flag("animate"));
+        assert!(!args.flag("-show"));
+    }
+
+    #[test]
+    fn test_values() -> Result<()> {
+        let args = args("--break ip=3 --trace out.jsonl --break 7");
+        assert_eq!(args.values("break")?, vec!["ip=3", "7"]);
+        assert_eq!(args.value("trace")?, Some("out.jsonl"));
+        assert_eq!(args.value("assemble")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_value() {
+        let err = args("--show --bytes").value("bytes").unwrap_err();
+        assert_eq!(err.to_string(), "expected --bytes <value>");
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let args = args("--size 6 --bytes twelve");
+        assert_eq!(args.parse::<usize>("size")?, Some(6));
+        assert_eq!(args.parse::<usize>("width")?, None);
+        let err = args.parse::<usize>("bytes").unwrap_err();
+        assert_eq!(err.to_string(), "invalid value \"twelve\" for --bytes");
+        Ok(())
+    }
+}